@@ -0,0 +1,78 @@
+//! Measures how many steps per second the processor runs on a large grid.
+//! Run with 'cargo bench'.
+
+use std::time::Instant;
+
+use cellm::{tokenizer::Tokenizer, parser::Parser, lower::lower, processor::Processor, config::{UpdateMode, WorldConfig}};
+
+const CONWAY:&str = "
+states 3
+1 =1.1 2 _ 2
+1 =0.1 2 _ 2
+1 =2.1 1 _ 1
+1 =3.1 1 _ 1
+1 ^4.1 2 _ 2
+2 =3.1 1 _ 1
+render 1 1000 1000
+";
+
+const STEPS:u32 = 10;
+
+/// Builds a 1000x1000 Game of Life grid, a quarter of it alive, using the given update mode.
+fn conway(update:UpdateMode) -> Processor {
+    let mut t = Tokenizer::new(CONWAY.to_string());
+    t.start().unwrap();
+    let program = lower(&Parser::new(t.tokens).start().unwrap()).ok().unwrap();
+
+    let mut world = WorldConfig::new_blank();
+    world.update = update;
+    let mut p = Processor::new_with_world(program.rule_set, program.render_rules, world);
+    p.reseed(1);
+    // Measure stepping alone, history is for interactive runs.
+    p.set_history_limit(0);
+    for y in 0..1000 {
+        for x in 0..1000 {
+            p.set_cell(2, x, y);
+        }
+    }
+    p.gen_random_seed(vec![1]).unwrap();
+    p
+}
+
+/// Steps the processor and prints how fast it went.
+fn measure(name:&str, p:&mut Processor) {
+    let start = Instant::now();
+    for _ in 0..STEPS {
+        p.step();
+    }
+    let secs = start.elapsed().as_secs_f64();
+    println!("{:<24} 1000x1000  {:>8.2} steps/sec  ({:.1} ms/step)", name, STEPS as f64 / secs, secs * 1000.0 / STEPS as f64);
+}
+
+fn main() {
+    for name in ["synchronous", "sequential", "random", "margolus"] {
+        measure(name, &mut conway(UpdateMode::from_name(name).unwrap()));
+    }
+
+    // A sparse grid that settles quickly, where only a few cells change each step.
+    for tracking in [false, true] {
+        let mut p = conway(UpdateMode::Synchronous);
+        p.dirty_tracking = tracking;
+        for y in 0..1000 {
+            for x in 0..1000 {
+                p.set_cell(2, x, y);
+            }
+        }
+        let mut r:u64 = 88172645463325252;
+        for _ in 0..5000 {
+            r ^= r << 13;
+            r ^= r >> 7;
+            r ^= r << 17;
+            p.set_cell(1, (r % 1000) as usize, (r / 1000 % 1000) as usize);
+        }
+        for _ in 0..20 {
+            p.step();
+        }
+        measure(if tracking { "settled, dirty tracking" } else { "settled, full scan" }, &mut p);
+    }
+}
@@ -0,0 +1,244 @@
+use std::fmt;
+
+use crate::tokenizer::{Token, TokenType};
+
+/// A region of a single line of source text.
+/// Lines and columns start at 1, end_col points one past the last char.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Span {
+    pub line:usize,
+    pub col:usize,
+    pub end_col:usize
+}
+
+impl Span {
+    pub fn new(line:usize, col:usize, end_col:usize) -> Span {
+        Span { line, col, end_col }
+    }
+
+    /// The span covered by a token in the source text.
+    pub fn of(t:&Token) -> Span {
+        let len = match t.ttype {
+            TokenType::Comment => t.lexeme.chars().count() + 1,
+            TokenType::Tab | TokenType::Newline | TokenType::EOF => 1,
+            _ => t.lexeme.chars().count()
+        };
+        Span::new(t.line, t.col, t.col + len)
+    }
+
+    /// Joins two spans on the same line into one span covering both.
+    pub fn to(&self, other:Span) -> Span {
+        Span::new(self.line, self.col, other.end_col.max(self.end_col))
+    }
+
+    /// Returns true if the given line and column are inside of the span.
+    pub fn contains(&self, line:usize, col:usize) -> bool {
+        self.line == line && col >= self.col && col < self.end_col
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// A number written in the source, along with where it was written.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Number {
+    pub value:i32,
+    pub span:Span
+}
+
+/// A number with an optional fraction, e.g. '0.5', along with where it was written.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Decimal {
+    pub value:f32,
+    pub span:Span
+}
+
+/// Root of the syntax tree for a cell-machine source file.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct System {
+    pub header:Option<Header>,
+    pub rules:Vec<RuleNode>,
+    pub render:Option<RenderSection>,
+    pub seed:Option<SeedSection>,
+    pub world:Option<WorldSection>,
+    pub layers:Vec<LayerSection>,
+    pub layer_seeds:Vec<SeedSection>,
+    pub comments:Vec<Comment>
+}
+
+/// The 'states N' line, optionally followed by a name for each state starting at state 0.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Header {
+    pub n_states:Number,
+    pub names:Vec<Name>,
+    pub span:Span
+}
+
+/// The name of a state, as declared in the header.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Name {
+    pub text:String,
+    pub span:Span
+}
+
+/// A single rule line, e.g. '1 ^4.1 2 _ 2'.
+#[derive(Clone, PartialEq, Debug)]
+pub struct RuleNode {
+    pub owner:Number,
+    pub neighbors:NeighborClause,
+    pub offspring:Option<Number>,   // None when written as '_'
+    pub move_to:MoveNode,
+    pub next:Number,
+    pub layers:Vec<LayerCondition>, // Conditions on layers written after the rule, all of which must hold
+    pub fields:Vec<FieldTerm>,      // Conditions on fields and amounts deposited into them, written after the rule
+    pub span:Span
+}
+
+/// The neighbor condition of a rule.
+#[derive(Clone, PartialEq, Debug)]
+pub enum NeighborClause {
+    /// A single neighbor by index, e.g. '2', in state 0 unless a state is given, e.g. '4.1'.
+    Explicit { index:Number, state:Option<Number>, span:Span },
+    /// A count of neighbors in a state, e.g. '^3.1' (at least) or '=3.1' (exactly).
+    Any { exact:bool, count:Option<Number>, state:Number, span:Span },
+    /// Every neighbor in a state, e.g. '*.0'.
+    All { state:Number, span:Span }
+}
+
+impl NeighborClause {
+    pub fn span(&self) -> Span {
+        match self {
+            NeighborClause::Explicit { span, .. } => *span,
+            NeighborClause::Any { span, .. } => *span,
+            NeighborClause::All { span, .. } => *span
+        }
+    }
+}
+
+/// A condition on a layer written after a rule, e.g. 'terrain.wall' or 'terrain ^2.water'.
+/// The layer and state are kept as written, since layers are declared after the rules.
+#[derive(Clone, PartialEq, Debug)]
+pub struct LayerCondition {
+    pub layer:Name,
+    pub clause:LayerClause,
+    pub state:Name,
+    pub span:Span
+}
+
+/// Which cells of a layer a condition reads, around the cell applying the rule.
+#[derive(Clone, PartialEq, Debug)]
+pub enum LayerClause {
+    /// The cell at the same position, e.g. 'terrain.wall'.
+    Here,
+    /// A single neighbor by index, e.g. 'terrain 0.wall'.
+    Explicit { index:Number },
+    /// A count of neighbors, e.g. 'terrain ^2.water' (at least) or 'terrain =2.water' (exactly).
+    Any { exact:bool, count:Option<Number> },
+    /// One of the neighbors, e.g. 'terrain *.soil', like '*' in a neighbor clause.
+    All
+}
+
+/// A field term written after a rule, e.g. 'field(pher)>0.5' or 'field(pher)+1'.
+/// The field is kept as written, since fields are declared in the world section after the rules.
+#[derive(Clone, PartialEq, Debug)]
+pub struct FieldTerm {
+    pub field:Name,
+    pub op:FieldOp,
+    pub amount:Decimal,
+    pub span:Span
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FieldOp {
+    Above,      // > The field at the cell is above the amount.
+    Below,      // < The field at the cell is below the amount.
+    Deposit     // + The amount is added to the field where the cell was.
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MoveKind {
+    Direction(char),    // l, r, u, d
+    Random,             // ^
+    Stay,               // _
+    Absorb              // @
+}
+
+/// Where a cell moves to when its rule is applied.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MoveNode {
+    pub kind:MoveKind,
+    pub span:Span
+}
+
+/// The 'render' section: sizes followed by a color for each state.
+#[derive(Clone, PartialEq, Debug)]
+pub struct RenderSection {
+    pub cell_size:Number,
+    pub grid_width:Number,
+    pub grid_height:Number,
+    pub colors:Vec<ColorNode>,
+    pub span:Span
+}
+
+/// A 'state color' line in the render section.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ColorNode {
+    pub state:Number,
+    pub color:u32,
+    pub span:Span
+}
+
+/// The 'seed' section: cells placed on the grid before the simulation starts.
+/// 'seed <layer>' places cells on a layer instead.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SeedSection {
+    pub layer:Option<Name>,
+    pub points:Vec<SeedPoint>,
+    pub span:Span
+}
+
+/// A 'layer' section: a grid of static cells under the main one, with its own states and a color for each of them.
+#[derive(Clone, PartialEq, Debug)]
+pub struct LayerSection {
+    pub name:Name,
+    pub n_states:Number,
+    pub names:Vec<Name>,
+    pub colors:Vec<ColorNode>,
+    pub span:Span
+}
+
+/// An 'x y state' line in the seed section.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SeedPoint {
+    pub x:Number,
+    pub y:Number,
+    pub state:Number,
+    pub span:Span
+}
+
+/// The 'world' section: settings for how the simulation runs.
+#[derive(Clone, PartialEq, Debug)]
+pub struct WorldSection {
+    pub settings:Vec<Setting>,
+    pub span:Span
+}
+
+/// A 'key value...' line in the world section, e.g. 'update margolus' or 'field pher 0.2 0.05'.
+/// Values are kept as written and interpreted when lowering.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Setting {
+    pub key:Name,
+    pub values:Vec<Name>,
+    pub span:Span
+}
+
+/// A comment, without the leading '#'.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Comment {
+    pub text:String,
+    pub span:Span
+}
@@ -1,5 +1,5 @@
 
-use std::collections::hash_map::DefaultHasher;
+use std::collections::{hash_map::DefaultHasher, HashSet};
 use std::hash::Hasher;
 
 use colour::green;
@@ -22,6 +22,38 @@ impl BioMove {
     }
 }
 
+/// Which cells of a layer a layer check reads, around the cell applying the rule.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LayerNeighbors {
+    Here,           // The cell at the same position.
+    One(usize),     // A single neighbor, numbered like the neighbors of the main grid.
+    AtLeast(i32),   // At least this many neighbors.
+    Exactly(i32)    // Exactly this many neighbors.
+}
+
+/// A condition on a layer under the grid that must hold for a rule to apply.
+#[derive(Clone, PartialEq, Debug)]
+pub struct LayerCheck {
+    pub layer:usize,                // Index of the layer, in the order they were declared
+    pub neighbors:LayerNeighbors,
+    pub state:i32                   // State the cells read should be in
+}
+
+/// A condition on the amount of a field at the cell applying a rule.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FieldCheck {
+    pub field:usize,        // Index of the field, in the order they were declared
+    pub above:bool,         // Set true if the amount should be above the value, or false for below
+    pub value:f32
+}
+
+/// An amount a rule adds to a field, where the cell was before it moved.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Deposit {
+    pub field:usize,
+    pub amount:f32
+}
+
 /// The BioRule struct is a representation of a rule written in a cell definition file.
 /// It has data that says what type of cell the rule is for, what neighbors to check and for what state,
 /// where the cell should move, and what state the cell should go to next.
@@ -36,7 +68,11 @@ pub struct BioRule {
     pub owner_state:i32,        // What the owner's state should be
     pub next_state:i32,         // Transorm state
     pub move_to:BioMove,        // Where to move after rule,
-    pub offspring:i32           // What the cell should leave behind if moving
+    pub offspring:i32,          // What the cell should leave behind if moving
+    pub layers:Vec<LayerCheck>, // Conditions on layers, which must all hold
+    pub field_checks:Vec<FieldCheck>, // Conditions on fields, which must all hold
+    pub deposits:Vec<Deposit>,  // Amounts added to fields when the rule is applied
+    pub id:u64                  // Unique id in its rule set, given by RuleSet::new
 }
 
 impl BioRule {
@@ -44,7 +80,7 @@ impl BioRule {
         BioRule { neighbors:vec![], neighbors_state:0, owner_state:0,
              next_state:0, move_to:BioMove::new_const('_'),
             any_neighbor:false,
-            any_neighbor_state:false, offspring:0, any_neighbor_count:1, any_neighbor_exact:false }
+            any_neighbor_state:false, offspring:0, any_neighbor_count:1, any_neighbor_exact:false, layers:vec![], field_checks:vec![], deposits:vec![], id:0 }
     }
 
     /// Useful for debugging the parser and processor.
@@ -64,14 +100,32 @@ impl BioRule {
         println!("NState: {}\tMove:{}\tOffspring:{}\tNext:{}\tAnyNeigh:{}\tExact:{}", self.neighbors_state, self.move_to.constant, self.offspring, self.next_state, self.any_neighbor, self.any_neighbor_exact);        
     }
 
-    /// Used for assigning a unique id to a rule for debugging purposes.
+    /// Hashes everything that makes up the rule, so rules written differently get different hashes.
+    /// Rules written the same way hash the same, RuleSet::new tells them apart when it gives out ids.
     pub fn calc_hash(&self) -> u64 {
         let mut s = DefaultHasher::new();
-        s.write_i32(self.neighbors_state);
-        s.write_i32(self.next_state);
-        s.write_i32(self.offspring);
         s.write_i32(self.owner_state);
+        for n in self.neighbors.iter() {
+            s.write_i32(*n);
+        }
+        s.write_i32(self.neighbors_state);
+        s.write_u8(self.any_neighbor as u8);
         s.write_i32(self.any_neighbor_count);
+        s.write_u8(self.any_neighbor_state as u8);
+        s.write_u8(self.any_neighbor_exact as u8);
+        s.write_i32(self.offspring);
+        s.write_u8(self.move_to.is_random as u8);
+        s.write_u32(self.move_to.constant as u32);
+        s.write_i32(self.next_state);
+        for c in self.layers.iter() {
+            s.write(format!("{}{:?}{}", c.layer, c.neighbors, c.state).as_bytes());
+        }
+        for c in self.field_checks.iter() {
+            s.write(format!("{}{}{}", c.field, c.above, c.value).as_bytes());
+        }
+        for d in self.deposits.iter() {
+            s.write(format!("{}+{}", d.field, d.amount).as_bytes());
+        }
         s.finish()
     }
 }
@@ -93,6 +147,14 @@ impl RuleSet {
             rs.rules[ (r.owner_state - 1) as usize].push(r.clone());
         }
 
+        // Ids are the rule hashes, and a rule written the same way as an earlier one takes the next free id.
+        let mut taken = HashSet::new();
+        for r in rs.rules.iter_mut().flatten() {
+            r.id = r.calc_hash();
+            while !taken.insert(r.id) {
+                r.id = r.id.wrapping_add(1);
+            }
+        }
         rs
     }
 
@@ -106,15 +168,100 @@ impl RuleSet {
         }
     }
 
+    /// Returns true if any rule moves its cell to a random neighbor.
+    pub fn has_random_moves(&self) -> bool {
+        self.rules.iter().flatten().any(|r| r.move_to.is_random)
+    }
+
+    /// Returns true if any rule reads a layer.
+    pub fn has_layer_checks(&self) -> bool {
+        self.rules.iter().flatten().any(|r| !r.layers.is_empty())
+    }
+
+    /// Returns true if any rule reads a field.
+    pub fn has_field_checks(&self) -> bool {
+        self.rules.iter().flatten().any(|r| !r.field_checks.is_empty())
+    }
+
+    /// Compiles the rule set into a lookup table. Returns None if a rule checks explicit neighbors, layers or fields,
+    /// or if the rules of a state count neighbors in more than MAX_COUNTED_STATES different states.
+    pub fn compile(&self) -> Option<RuleTable> {
+        let mut table = RuleTable { counted:vec![], matches:vec![] };
+
+        for rules in self.rules.iter() {
+            if rules.iter().any(|r| !r.any_neighbor || !r.layers.is_empty() || !r.field_checks.is_empty()) {
+                return None;
+            }
+            let mut counted:Vec<i32> = vec![];
+            for r in rules.iter() {
+                if !counted.contains(&r.neighbors_state) {
+                    counted.push(r.neighbors_state);
+                }
+            }
+            if counted.len() > MAX_COUNTED_STATES {
+                return None;
+            }
+
+            // Find the last matching rule for every combination of counts.
+            let size = 9usize.pow(counted.len() as u32);
+            let mut matches = vec![NO_RULE; size];
+            for (index, m) in matches.iter_mut().enumerate() {
+                let count_of = |state:i32| {
+                    let k = counted.iter().position(|c| *c == state).unwrap();
+                    (index / 9usize.pow(k as u32) % 9) as i32
+                };
+                let last = rules.iter().rposition(|r| {
+                    let n = count_of(r.neighbors_state);
+                    if r.any_neighbor_exact { n == r.any_neighbor_count } else { n >= r.any_neighbor_count }
+                });
+                if let Some(i) = last {
+                    *m = i as u32;
+                }
+            }
+            table.counted.push(counted);
+            table.matches.push(matches);
+        }
+        Some(table)
+    }
+
     pub fn print(&self) {
         println!("{} state RuleSet:", self.nstates);
         for i in 0..self.rules.len() {
             
             for j in 0..self.rules[i].len() {
-                green!("({},{}) - {:4}:\n\t", i, j, self.rules[i][j].calc_hash());
+                green!("({},{}) - {:016x}:\n\t", i, j, self.rules[i][j].id);
                 self.rules[i][j].print();
             }
             println!();
         }
     }
-}
\ No newline at end of file
+}
+
+/// Most neighbor states the rules of one state may count before a table gets too big to be worth it.
+pub const MAX_COUNTED_STATES:usize = 3;
+
+const NO_RULE:u32 = u32::MAX;
+
+/// A rule set made only of neighbor count conditions, compiled into tables.
+/// The rule that applies to a cell is looked up by its state and how many of its neighbors are in each counted state,
+/// instead of checking every rule.
+#[derive(Clone)]
+pub struct RuleTable {
+    counted:Vec<Vec<i32>>,  // States whose neighbors are counted, for each owner state starting at state 1
+    matches:Vec<Vec<u32>>   // Last matching rule for each combination of counts, for each owner state
+}
+
+impl RuleTable {
+    /// The states whose neighbors are counted for cells in the given state.
+    pub fn counted(&self, state:usize) -> &[i32] {
+        &self.counted[state - 1]
+    }
+
+    /// Returns the index of the rule that applies to a cell in the given state, or None if no rule matches.
+    /// counts holds the number of neighbors in each of the counted states, in the same order.
+    pub fn lookup(&self, state:usize, counts:&[usize]) -> Option<usize> {
+        let index = counts.iter().rev().fold(0, |acc, c| acc * 9 + c);
+        let m = self.matches[state - 1][index];
+        if m == NO_RULE { None } else { Some(m as usize) }
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt;
+
+use crate::{tokenizer::Tokenizer, parser::Parser, lower::lower, ast::*};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Severity {
+    Error,      // The file cannot be simulated, or the simulation would crash.
+    Warning     // The file runs, but probably not the way it was meant to.
+}
+
+/// A problem found in a source file by the check pass.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Diagnostic {
+    pub severity:Severity,
+    pub msg:String,
+    pub span:Span
+}
+
+impl Diagnostic {
+    fn error(msg:String, span:Span) -> Diagnostic {
+        Diagnostic { severity:Severity::Error, msg, span }
+    }
+
+    fn warning(msg:String, span:Span) -> Diagnostic {
+        Diagnostic { severity:Severity::Warning, msg, span }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning"
+        };
+        write!(f, "{}: {}: {}", self.span, kind, self.msg)
+    }
+}
+
+/// Runs the tokenizer, parser, lowering and check passes on a source string.
+/// Returns the syntax tree if parsing succeeded, and every problem found.
+pub fn check_source(text:&str) -> (Option<System>, Vec<Diagnostic>) {
+    let mut t = Tokenizer::new(text.to_string());
+    if let Err(e) = t.start() {
+        let (line, col) = t.position();
+        return (None, vec![Diagnostic::error(e.to_string().trim().to_string(), Span::new(line, col, col + 1))]);
+    }
+
+    let system = match Parser::new(t.tokens).start() {
+        Ok(s) => s,
+        Err(e) => return (None, vec![Diagnostic::error(e.msg, e.span)])
+    };
+    let diagnostics = check(&system);
+    (Some(system), diagnostics)
+}
+
+/// Finds errors that would stop or crash a simulation, and warns about rules that can never matter.
+pub fn check(system:&System) -> Vec<Diagnostic> {
+    let mut diagnostics:Vec<Diagnostic> = match lower(system) {
+        Ok(_) => vec![],
+        Err(errors) => errors.into_iter().map(|e| Diagnostic::error(e.msg, e.span)).collect()
+    };
+    let header = match &system.header {
+        Some(h) => h,
+        None => return diagnostics
+    };
+    let n_states = header.n_states.value;
+
+    // Every state that can be on the grid is drawn by the renderer, so every state needs a color.
+    match &system.render {
+        Some(render) => {
+            if render.grid_width.value == 0 || render.grid_height.value == 0 {
+                diagnostics.push(Diagnostic::error(String::from("The grid needs a width and height of at least 1."), render.span));
+            }
+            for s in 0..n_states {
+                if !render.colors.iter().any(|c| c.state.value == s) {
+                    diagnostics.push(Diagnostic::error(format!("State {} has no color in the render section.", s), render.span));
+                }
+            }
+        },
+        None => diagnostics.push(Diagnostic::error(String::from("Missing 'render' section, no state has a color."), header.span))
+    };
+
+    // Warn about states that only appear through random seeding.
+    for s in 1..n_states {
+        let produced = system.rules.iter().any(|r| r.next.value == s || r.offspring.as_ref().is_some_and(|o| o.value == s));
+        let seeded = system.seed.as_ref().is_some_and(|seed| seed.points.iter().any(|p| p.state.value == s));
+        if !produced && !seeded {
+            diagnostics.push(Diagnostic::warning(format!("State {} is never produced by a rule or placed by the seed section.", s), header.span));
+        }
+    }
+
+    // Warn about next states that never change again.
+    let mut reported:Vec<i32> = vec![];
+    for rule in system.rules.iter() {
+        let s = rule.next.value;
+        if s != 0 && s < n_states && !reported.contains(&s) && !system.rules.iter().any(|r| r.owner.value == s) {
+            diagnostics.push(Diagnostic::warning(format!("State {} has no rules, cells that reach it will never change.", s), rule.next.span));
+            reported.push(s);
+        }
+    }
+
+    // The processor applies the last rule that matches a cell, so a rule is dead if a later rule always matches with it.
+    for (i, rule) in system.rules.iter().enumerate() {
+        let shadow = system.rules[i + 1..].iter().find(|later| later.owner.value == rule.owner.value && implies(&rule.neighbors, &later.neighbors)
+            && later.layers.iter().all(|c| rule.layers.iter().any(|e| same_condition(e, c)))
+            && later.fields.iter().filter(|t| t.op != FieldOp::Deposit).all(|t| rule.fields.iter().any(|e| same_field_check(e, t))));
+        if let Some(later) = shadow {
+            diagnostics.push(Diagnostic::warning(format!("Rule is never applied, it is shadowed by the rule on line {}.", later.span.line), rule.span));
+        }
+    }
+
+    diagnostics
+}
+
+/// Returns true if two layer conditions are written the same way.
+fn same_condition(a:&LayerCondition, b:&LayerCondition) -> bool {
+    let count = |c:&Option<Number>| c.as_ref().map_or(1, |n| n.value);
+    let same_clause = match (&a.clause, &b.clause) {
+        (LayerClause::Here, LayerClause::Here) | (LayerClause::All, LayerClause::All) => true,
+        (LayerClause::Explicit { index:i }, LayerClause::Explicit { index:j }) => i.value == j.value,
+        (LayerClause::Any { exact:e1, count:c1 }, LayerClause::Any { exact:e2, count:c2 }) => e1 == e2 && count(c1) == count(c2),
+        _ => false
+    };
+    same_clause && a.layer.text == b.layer.text && a.state.text == b.state.text
+}
+
+/// Returns true if two field terms check the same field against the same amount.
+fn same_field_check(a:&FieldTerm, b:&FieldTerm) -> bool {
+    a.field.text == b.field.text && a.op == b.op && a.amount.value == b.amount.value
+}
+
+/// Returns true if the later neighbor clause holds whenever the earlier one does.
+fn implies(earlier:&NeighborClause, later:&NeighborClause) -> bool {
+    use NeighborClause::*;
+    let count = |c:&Option<Number>| c.as_ref().map_or(1, |n| n.value);
+    let explicit = |s:&Option<Number>| s.as_ref().map_or(0, |n| n.value);
+
+    match (earlier, later) {
+        // At least 0 neighbors always matches.
+        (_, Any { exact:false, count:c, .. }) if count(c) == 0 => true,
+        (Explicit { index:a, state:s1, .. }, Explicit { index:b, state:s2, .. }) => a.value == b.value && explicit(s1) == explicit(s2),
+        (Explicit { state:s1, .. }, All { state:s2, .. }) => explicit(s1) == s2.value,
+        (Explicit { state:s1, .. }, Any { exact:false, count:c, state:s2, .. }) => explicit(s1) == s2.value && count(c) <= 1,
+        (Any { count:a, state:s1, .. }, All { state:s2, .. }) => s1.value == s2.value && count(a) >= 1,
+        (Any { exact:e1, count:a, state:s1, .. }, Any { exact:e2, count:b, state:s2, .. }) => {
+            s1.value == s2.value && match (e1, e2) {
+                (true, true) => count(a) == count(b),
+                (_, false) => count(a) >= count(b),
+                (false, true) => false
+            }
+        },
+        (All { state:s1, .. }, All { state:s2, .. }) => s1.value == s2.value,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_source, Severity};
+
+    const RENDER:&str = "render 10 10 10\n0 000000FF\n1 FFFFFFFF\n2 FF0000FF\n";
+
+    #[test]
+    fn reports_crashing_states_as_errors() {
+        let (_, diagnostics) = check_source("states 2\n1 *.0 _ _ 2\nrender 10 10 10\n0 000000FF\n");
+        let errors:Vec<String> = diagnostics.iter().filter(|d| d.severity == Severity::Error).map(|d| d.msg.clone()).collect();
+
+        assert!(errors[0].contains("State 2 is out of range"));
+        assert!(errors[1].contains("State 1 has no color"));
+    }
+
+    #[test]
+    fn accepts_a_single_state() {
+        // Random seeding is only done with -gen, and does nothing for a system without living states.
+        let (_, diagnostics) = check_source("states 1\nrender 10 10 10\n0 000000FF\n");
+        assert_eq!(diagnostics, vec![]);
+    }
+
+    #[test]
+    fn warns_about_shadowed_rules() {
+        let src = format!("states 3\n1 =3.1 2 _ 2\n1 ^2.1 1 _ 1\n2 ^.1 _ _ 1\n{}", RENDER);
+        let (_, diagnostics) = check_source(&src);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].span.line, 2);
+    }
+
+    #[test]
+    fn layer_and_field_conditions_keep_rules_apart() {
+        let src = format!("states 3\n1 ^.1 1 _ 1 ground.1\n1 ^.1 2 _ 2 ground.2\n2 ^.1 1 _ 1\n2 ^.1 1 _ 1 ground.1\n{}layer ground 3\n", RENDER);
+        let (_, diagnostics) = check_source(&src);
+
+        // Only a later rule without the condition shadows one with it.
+        assert_eq!(diagnostics.len(), 0);
+        let src = format!("states 3\n1 ^.1 1 _ 1 ground.1\n1 ^.1 2 _ 2\n2 ^.1 1 _ 1\n{}layer ground 3\n", RENDER);
+        assert_eq!(check_source(&src).1[0].span.line, 2);
+
+        // Field checks work the same way, but deposits do not change which cells a rule matches.
+        let src = format!("states 3\n1 ^.1 1 _ 1\n1 ^.1 2 _ 2 field(pher)>1\n2 ^.1 1 _ 1\n{}world\nfield pher 0 0\n", RENDER);
+        assert_eq!(check_source(&src).1.len(), 0);
+        let src = format!("states 3\n1 ^.1 1 _ 1\n1 ^.1 2 _ 2 field(pher)+1\n2 ^.1 1 _ 1\n{}world\nfield pher 0 0\n", RENDER);
+        assert_eq!(check_source(&src).1[0].span.line, 2);
+    }
+
+    #[test]
+    fn warns_about_dead_end_states() {
+        let src = format!("states 3\n1 ^.1 _ _ 2\n{}", RENDER);
+        let (_, diagnostics) = check_source(&src);
+
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0].msg.contains("State 1 is never produced"));
+        assert!(diagnostics[1].msg.contains("State 2 has no rules"));
+    }
+}
@@ -1,20 +1,44 @@
 use std::{path::Path, io};
 use colour::yellow_ln;
 
+use crate::trace::Breakpoint;
+
+/// The action requested on the command line.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Command {
+    Simulate,       // Default, run the simulation in a window.
+    Run,            // Run the simulation for a number of steps without a window and write out the result.
+    Fmt,            // Pretty-print the source file.
+    Lsp,            // Run the language server over stdio.
+    Check           // Report errors and warnings in the source file.
+}
+
 /// Structure representing the possible command line arguments.
 #[derive(Clone)]
 pub struct Arguments {
+    pub command:Command,
     pub file_path:String,
     pub window_width:usize,
     pub window_height:usize,
     pub verbose:bool,
     pub fill_state:i32,
-    pub gen_states:Vec<i32>
+    pub gen:bool,               // Place random cells at the start, given -gen.
+    pub gen_states:Vec<i32>,    // States -gen picks from, any living state if empty.
+    pub check:bool,
+    pub deny_warnings:bool,
+    pub seed:Option<u64>,       // Seed for the random number generator, picked at random if not given.
+    pub steps:Option<u64>,      // Number of steps to run for, required by 'run'.
+    pub out:Option<String>,     // File to write the result of 'run' to, printed if not given.
+    pub until_settled:bool,     // Stop a 'run' early once the simulation dies out, stops changing or repeats itself.
+    pub stats:Option<String>,   // File to stream the stats of every step of a 'run' to.
+    pub image:Option<String>,   // File to write a picture of the end of a 'run' to.
+    pub trace:Option<String>,   // File to write every rule application to.
+    pub breakpoints:Vec<Breakpoint> // Conditions that pause the simulation, or stop a 'run'.
 }
 
 impl Arguments {
     pub fn new_blank() -> Arguments {
-        Arguments {file_path:String::new(), window_width:0, window_height:0, verbose:false, fill_state:0, gen_states:vec![] }
+        Arguments { command:Command::Simulate, file_path:String::new(), window_width:0, window_height:0, verbose:false, fill_state:0, gen:false, gen_states:vec![], check:false, deny_warnings:false, seed:None, steps:None, out:None, until_settled:false, stats:None, image:None, trace:None, breakpoints:vec![] }
     }
 }
 
@@ -34,11 +58,13 @@ impl ArgParseState {
         ArgParseState { args:args, cur_arg:cur_a, cur_arg_index:0, result:Arguments::new_blank() }
     }
 
-    fn parse(&mut self, fp:String) -> io::Result<Arguments> {
+    fn parse(&mut self, command:Command, fp:String) -> io::Result<Arguments> {
+        self.result.command = command;
         self.result.file_path = fp;
 
         while self.cur_arg_index < self.args.len() {
-            
+            let index = self.cur_arg_index;
+
             // parsing window size
             if self.cur_arg == "-size" {
                 self.advance();
@@ -69,11 +95,130 @@ impl ArgParseState {
             }
 
             if self.cur_arg == "-gen" {
+                self.result.gen = true;
                 if !self.parse_gen() {
                     return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter given for -gen option. Expecting a valid list of <state>."));
                 }
             }
 
+            // Only report formatting problems instead of rewriting the file.
+            if self.cur_arg == "--check" {
+                if self.result.command != Command::Fmt {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "The --check option is only valid for 'fmt'."));
+                }
+                self.advance();
+                self.result.check = true;
+            }
+
+            // Treat warnings as errors when checking.
+            if self.cur_arg == "--deny-warnings" {
+                if self.result.command != Command::Check {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "The --deny-warnings option is only valid for 'check'."));
+                }
+                self.advance();
+                self.result.deny_warnings = true;
+            }
+
+            // Seed the random number generator to make a run reproducible.
+            if self.cur_arg == "--seed" {
+                if self.result.command != Command::Simulate && self.result.command != Command::Run {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "The --seed option is only valid when running a simulation."));
+                }
+                self.advance();
+                match self.cur_arg.parse::<u64>() {
+                    Ok(n) if self.cur_arg_index < self.args.len() => self.result.seed = Some(n),
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter given for --seed option. Expecting a positive whole number."))
+                };
+                self.advance();
+            }
+
+            // Number of steps for a headless run.
+            if self.cur_arg == "--steps" {
+                if self.result.command != Command::Run {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "The --steps option is only valid for 'run'."));
+                }
+                self.advance();
+                match self.cur_arg.parse::<u64>() {
+                    Ok(n) if self.cur_arg_index < self.args.len() => self.result.steps = Some(n),
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter given for --steps option. Expecting a positive whole number."))
+                };
+                self.advance();
+            }
+
+            // Where a headless run writes its result.
+            if self.cur_arg == "--out" {
+                if self.result.command != Command::Run {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "The --out option is only valid for 'run'."));
+                }
+                if !self.advance() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Expecting a <file> after the --out option."));
+                }
+                self.result.out = Some(self.cur_arg.clone());
+                self.advance();
+            }
+
+            // Where a headless run streams the stats of each step.
+            if self.cur_arg == "--stats" {
+                if self.result.command != Command::Run {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "The --stats option is only valid for 'run'."));
+                }
+                if !self.advance() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Expecting a <file> after the --stats option."));
+                }
+                self.result.stats = Some(self.cur_arg.clone());
+                self.advance();
+            }
+
+            // Where a headless run draws its final grid, or space-time diagram.
+            if self.cur_arg == "--image" {
+                if self.result.command != Command::Run {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "The --image option is only valid for 'run'."));
+                }
+                if !self.advance() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Expecting a <file> after the --image option."));
+                }
+                self.result.image = Some(self.cur_arg.clone());
+                self.advance();
+            }
+
+            // Stop a headless run once it settles.
+            if self.cur_arg == "--until-settled" {
+                if self.result.command != Command::Run {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "The --until-settled option is only valid for 'run'."));
+                }
+                self.advance();
+                self.result.until_settled = true;
+            }
+
+            // Log every rule application.
+            if self.cur_arg == "--trace" {
+                if self.result.command != Command::Simulate && self.result.command != Command::Run {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "The --trace option is only valid when running a simulation."));
+                }
+                if !self.advance() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Expecting a <file> after the --trace option."));
+                }
+                self.result.trace = Some(self.cur_arg.clone());
+                self.advance();
+            }
+
+            // Pause when a condition holds, can be given more than once.
+            if self.cur_arg == "--break" {
+                if self.result.command != Command::Simulate && self.result.command != Command::Run {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "The --break option is only valid when running a simulation."));
+                }
+                let breakpoint = if self.advance() { Breakpoint::parse(&self.cur_arg) } else { None };
+                match breakpoint {
+                    Some(b) => self.result.breakpoints.push(b),
+                    None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter given for --break option. Expecting \"rule <id> [at <x> <y>]\" or \"count <state> <n>\"."))
+                };
+                self.advance();
+            }
+
+            // Stop on anything that was not consumed by the options above.
+            if index == self.cur_arg_index {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown argument '{}'.", self.cur_arg)));
+            }
         }
         Ok(self.result.clone())
     }
@@ -134,7 +279,24 @@ impl ArgParseState {
 
 /// Attempts to parse command line arguments for the command line interface.
 /// Accepts a vector string of args that should omit the working directory argument.
+/// A subcommand such as 'fmt' may be given before the file path.
 pub fn parse_args(args:&Vec<String>) -> io::Result<Arguments> {
+    let (command, args) = match args[0].as_ref() {
+        "fmt" => (Command::Fmt, &args[1..]),
+        "lsp" => (Command::Lsp, &args[1..]),
+        "check" => (Command::Check, &args[1..]),
+        "run" => (Command::Run, &args[1..]),
+        _ => (Command::Simulate, &args[..])
+    };
+    // The language server gets its files from the editor.
+    if command == Command::Lsp {
+        let mut result = Arguments::new_blank();
+        result.command = command;
+        return Ok(result);
+    }
+    if args.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Expecting a <filename>."));
+    }
     let fp:&String = &args[0];
 
     // Check if the file exists
@@ -144,24 +306,48 @@ pub fn parse_args(args:&Vec<String>) -> io::Result<Arguments> {
     };
 
     // If there are more arguments besides the file path, parse them.
-    if args.len() > 1 {
+    let result = if args.len() > 1 {
         let mut parser = ArgParseState::new(args[1..].to_vec());
-        let p_result = parser.parse(fp.to_string());
-
-        p_result
+        parser.parse(command, fp.to_string())?
     } else {
-        Ok(Arguments { file_path:fp.to_string(), window_width:0, window_height:0, verbose:false, fill_state:0, gen_states:vec![] })
+        let mut result = Arguments::new_blank();
+        result.command = command;
+        result.file_path = fp.to_string();
+        result
+    };
+    if result.command == Command::Run && result.steps.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The 'run' command needs the number of steps to run, given with --steps <n>."));
     }
+    Ok(result)
 }
 
 /// Prints a help message to the console.
 pub fn print_help() {
-    colour::cyan!("CellM 0.1 --- Usage: cellm.exe <filename> [arguments...]");
+    colour::cyan!("CellM 0.1 --- Usage: cellm.exe [command] <filename> [arguments...]");
+    print!("
+Commands:
+    <filename>                        Run the simulation in a window. Needs a build with the gui feature.
+    fmt <filename> [--check]          Rewrite the file in the canonical layout. --check only reports unformatted files.
+    lsp                               Run the language server, speaking LSP over stdio.
+    check <filename> [--deny-warnings]
+                                      Report errors and warnings. Exits with status 1 if there are errors.
+    run <filename> --steps <n> [--out <file>] [--until-settled] [--stats <file>] [--image <file>]
+                                      Run <n> steps without a window, then write the final grid and stats as JSON.
+                                      --until-settled stops early once the grid dies out, stops changing or repeats.
+                                      --stats <file> writes the stats of every step, as CSV for .csv files or JSON Lines otherwise.
+                                      --image <file> draws the final grid as a PPM image, or every generation of a
+                                      one-dimensional world as a space-time diagram, one row per generation.
+");
     print!("
     -verbose                          Print output from tokenizer and parser.
     -help                             Print help screen.
-    -fill <state>                     Fill the grid with <state> cells at the start.
-    -gen  <state> ...                 Randomly place all given states into cells on the grid at the start.
+    -fill <state>                     Fill the empty cells of the grid with <state> at the start, after the seed section.
+    -gen  [<state> ...]               Randomly place the given states, or any living state, into cells on the grid at the start.
+    --seed <n>                        Seed the random number generator. Runs with the same seed give the same result.
+    --trace <file>                    Write a line for every rule applied: generation, x, y, rule id, state before and after.
+    --break <condition>               Pause the window, or stop a run, when the condition holds. Can be given more than once.
+                                      \"rule <id> [at <x> <y>]\" when the rule with that id fires, anywhere or at a position.
+                                      \"count <state> <n>\" when the number of cells in the state reaches <n>.
     -size <width> <height>            Indicate desired size of simulation window.");
 
     yellow_ln!("\t<- Not implemented for default renderer.");
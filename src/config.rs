@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 
+use crate::layer::Layer;
+
 pub struct StatePoint {
-    x:usize,
-    y:usize,
-    state:i32
+    pub x:usize,
+    pub y:usize,
+    pub state:i32
 }
 
 impl StatePoint {
@@ -17,12 +19,13 @@ pub struct RenderRules {
     pub cell_size:usize,
     pub grid_width:usize,
     pub grid_height:usize,
-    seed:Vec<StatePoint>
+    seed:Vec<StatePoint>,
+    layers:Vec<Layer>
 }
 
 impl RenderRules {
     pub fn new_blank() -> RenderRules {
-        RenderRules { colors:HashMap::new(), cell_size:10, grid_width:10, grid_height:10, seed:vec![] }
+        RenderRules { colors:HashMap::new(), cell_size:10, grid_width:10, grid_height:10, seed:vec![], layers:vec![] }
     }
 
     pub fn get_colors(&self) -> &HashMap<i32, u32> {
@@ -40,4 +43,103 @@ impl RenderRules {
     pub fn add_state_point(&mut self, sp:StatePoint) {
         self.seed.push(sp);
     }
-}
\ No newline at end of file
+
+    /// Cells to place on the grid before the simulation starts.
+    pub fn get_seed(&self) -> &Vec<StatePoint> {
+        &self.seed
+    }
+
+    pub fn add_layer(&mut self, layer:Layer) {
+        self.layers.push(layer);
+    }
+
+    /// Layers declared in the source, with their seed cells in place. The processor takes them when it is created.
+    pub fn take_layers(&mut self) -> Vec<Layer> {
+        std::mem::take(&mut self.layers)
+    }
+}
+
+/// The order in which cells are updated during a step.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UpdateMode {
+    Synchronous,    // Every cell reads the previous generation, writes go to a second buffer.
+    Sequential,     // Cells update in place, in row-major order.
+    RandomOrder,    // Cells update in place, in a new random order every step.
+    Margolus        // 2x2 blocks update on their own, the block grid shifts by one cell every step.
+}
+
+impl UpdateMode {
+    /// Reads the name of an update mode as written in the world section.
+    pub fn from_name(name:&str) -> Option<UpdateMode> {
+        match name {
+            "synchronous" => Some(UpdateMode::Synchronous),
+            "sequential" => Some(UpdateMode::Sequential),
+            "random" => Some(UpdateMode::RandomOrder),
+            "margolus" => Some(UpdateMode::Margolus),
+            _ => None
+        }
+    }
+}
+
+/// What a cell does when its rule moves it randomly, but none of its neighbors are empty.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlockedMove {
+    Stay,           // Apply the rule, but the cell stays where it is.
+    Skip,           // Do not apply the rule, the cell is left unchanged.
+    Next            // Try the next rule that matches the cell instead.
+}
+
+impl BlockedMove {
+    /// Reads the name of a blocked move fallback as written in the world section.
+    pub fn from_name(name:&str) -> Option<BlockedMove> {
+        match name {
+            "stay" => Some(BlockedMove::Stay),
+            "skip" => Some(BlockedMove::Skip),
+            "next" => Some(BlockedMove::Next),
+            _ => None
+        }
+    }
+}
+
+/// Whether the world ends at the edges of the grid from the render section.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Bounds {
+    Fixed,          // The grid keeps its size, cells cannot leave it.
+    Unbounded       // The grid grows and shrinks with the pattern, in chunks.
+}
+
+impl Bounds {
+    /// Reads the name of a bounds setting as written in the world section.
+    pub fn from_name(name:&str) -> Option<Bounds> {
+        match name {
+            "fixed" => Some(Bounds::Fixed),
+            "unbounded" => Some(Bounds::Unbounded),
+            _ => None
+        }
+    }
+}
+
+/// A 'field name diffusion decay' line from the world section.
+#[derive(Clone, PartialEq, Debug)]
+pub struct FieldConfig {
+    pub name:String,
+    pub diffusion:f32,      // Share of the difference with its neighbors' average a cell takes on every step, from 0 to 1.
+    pub decay:f32           // Share of the amount lost every step, from 0 to 1.
+}
+
+/// Settings from the world section that change how the simulation runs.
+#[derive(Clone, PartialEq, Debug)]
+pub struct WorldConfig {
+    pub update:UpdateMode,
+    pub blocked:BlockedMove,
+    pub bounds:Bounds,
+    pub fields:Vec<FieldConfig>,
+    pub dimensions:usize,       // 2 for a grid, or 1 for a single row drawn as a space-time diagram.
+    pub elementary:Option<u8>   // Wolfram rule number that drives a one-dimensional world instead of the rules.
+}
+
+impl WorldConfig {
+    pub fn new_blank() -> WorldConfig {
+        WorldConfig { update:UpdateMode::Synchronous, blocked:BlockedMove::Stay, bounds:Bounds::Fixed, fields:vec![], dimensions:2, elementary:None }
+    }
+}
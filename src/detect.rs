@@ -0,0 +1,112 @@
+use std::{collections::VecDeque, fmt};
+
+/// Longest cycle looked for by default.
+pub const DEFAULT_MAX_PERIOD:usize = 16;
+
+/// How a simulation has settled down.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Settled {
+    Extinct,        // Every cell is in the dead state.
+    StillLife,      // The grid stays the same from one generation to the next.
+    Cycle(usize)    // The grid repeats itself every so many generations.
+}
+
+impl fmt::Display for Settled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Settled::Extinct => write!(f, "extinct"),
+            Settled::StillLife => write!(f, "still life"),
+            Settled::Cycle(p) => write!(f, "period {} cycle", p)
+        }
+    }
+}
+
+/// Hash of a single cell, summed over every cell to get the hash of a grid.
+/// Dead cells add nothing, and the position is in world coordinates, so the sum can be kept up to date one cell at a time.
+pub(crate) fn cell_hash(x:i64, y:i64, state:i32) -> u64 {
+    if state == 0 {
+        return 0;
+    }
+    let mut z = (x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ (y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F) ^ (state as u64).wrapping_mul(0x1656_67B1_9E37_79F9);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Finds repeats among the hashes of the last few generations.
+/// Two grids with the same hash are taken to be the same grid.
+#[derive(Clone)]
+pub(crate) struct CycleDetector {
+    hashes:VecDeque<u64>,   // Hashes of the generations before the current one, newest last
+    pub max_period:usize
+}
+
+impl CycleDetector {
+    pub fn new(max_period:usize) -> CycleDetector {
+        CycleDetector { hashes:VecDeque::new(), max_period }
+    }
+
+    /// Records the hash of the generation a run starts from, unless something was recorded since the last reset.
+    pub fn begin(&mut self, hash:u64) {
+        if self.hashes.is_empty() {
+            self.hashes.push_back(hash);
+        }
+    }
+
+    /// Records the hash of a new generation, and returns how the simulation settled if it did.
+    /// A repeat only means a cycle if the rules are deterministic, and with stride 2 only even periods count,
+    /// for update modes that alternate between two kinds of step.
+    pub fn push(&mut self, hash:u64, extinct:bool, deterministic:bool, stride:usize) -> Option<Settled> {
+        let repeat = (1..=self.hashes.len().min(self.max_period))
+            .filter(|p| p % stride == 0)
+            .find(|p| self.hashes[self.hashes.len() - p] == hash);
+        let still = self.hashes.back() == Some(&hash);
+
+        self.hashes.push_back(hash);
+        while self.hashes.len() > self.max_period {
+            self.hashes.pop_front();
+        }
+
+        if extinct {
+            return Some(Settled::Extinct);
+        }
+        match (deterministic, repeat) {
+            (true, Some(p)) if still && p <= 2 => Some(Settled::StillLife),
+            (true, Some(p)) => Some(Settled::Cycle(p)),
+            _ => None
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.hashes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cell_hash, CycleDetector, Settled};
+
+    #[test]
+    fn finds_the_shortest_period() {
+        let mut d = CycleDetector::new(4);
+        let results:Vec<Option<Settled>> = [1, 2, 3, 1, 2, 3].iter().map(|h| d.push(*h, false, true, 1)).collect();
+        assert_eq!(results, vec![None, None, None, Some(Settled::Cycle(3)), Some(Settled::Cycle(3)), Some(Settled::Cycle(3))]);
+
+        assert_eq!(d.push(3, false, true, 1), Some(Settled::StillLife));
+        assert_eq!(d.push(3, false, false, 1), None);
+        assert_eq!(d.push(0, true, false, 1), Some(Settled::Extinct));
+
+        // Only even periods count with a stride of 2.
+        let mut d = CycleDetector::new(4);
+        assert_eq!(d.push(5, false, true, 2), None);
+        assert_eq!(d.push(5, false, true, 2), None);
+        assert_eq!(d.push(5, false, true, 2), Some(Settled::StillLife));
+    }
+
+    #[test]
+    fn cell_hashes_depend_on_position_and_state() {
+        assert_eq!(cell_hash(4, 7, 0), 0);
+        assert_ne!(cell_hash(4, 7, 1), cell_hash(7, 4, 1));
+        assert_ne!(cell_hash(4, 7, 1), cell_hash(4, 7, 2));
+    }
+}
@@ -0,0 +1,147 @@
+/// A grid of amounts that spread out to neighboring cells and fade away every step, such as a pheromone.
+/// Cells are at world positions, starting at (0, 0) and the size of the render grid. Amounts outside of it read as 0.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Field {
+    pub name:String,
+    pub diffusion:f32,      // Share of the difference with its neighbors' average a cell takes on every step, from 0 to 1.
+    pub decay:f32,          // Share of the amount lost every step, from 0 to 1.
+    width:usize,
+    height:usize,
+    values:Vec<f32>,
+    back:Vec<f32>           // Values being computed during diffuse, then the values from before it.
+}
+
+impl Field {
+    pub fn new(name:String, diffusion:f32, decay:f32, width:usize, height:usize) -> Field {
+        Field { name, diffusion, decay, width, height, values:vec![0.0; width * height], back:vec![0.0; width * height] }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Amount at world position (x, y), 0 outside of the field.
+    pub fn get(&self, x:i64, y:i64) -> f32 {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return 0.0;
+        }
+        self.values[y as usize * self.width + x as usize]
+    }
+
+    /// Adds an amount at world position (x, y). Returns false if it is outside of the field.
+    pub fn add(&mut self, x:i64, y:i64, amount:f32) -> bool {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return false;
+        }
+        self.values[y as usize * self.width + x as usize] += amount;
+        true
+    }
+
+    /// Every amount in row-major order.
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+
+    /// Index and amount from before the last diffuse of every amount that changed since.
+    /// Used to go back through the history without keeping a copy of the whole field.
+    pub(crate) fn changes(&self) -> Vec<(usize, f32)> {
+        self.values.iter().zip(self.back.iter()).enumerate()
+            .filter(|(_, (new, old))| new.to_bits() != old.to_bits())
+            .map(|(i, (_, old))| (i, *old))
+            .collect()
+    }
+
+    /// Puts back amounts by their index, as returned by changes.
+    pub(crate) fn restore(&mut self, changes:Vec<(usize, f32)>) {
+        for (i, old) in changes {
+            self.values[i] = old;
+        }
+    }
+
+    /// The largest amount in the field, or 0 if it is empty.
+    pub fn max(&self) -> f32 {
+        self.values.iter().fold(0.0, |m, v| m.max(*v))
+    }
+
+    /// Changes the size of the field, keeping the amounts that still fit.
+    pub fn resize(&mut self, width:usize, height:usize) {
+        let mut values = vec![0.0; width * height];
+        for y in 0..height.min(self.height) {
+            for x in 0..width.min(self.width) {
+                values[y * width + x] = self.values[y * self.width + x];
+            }
+        }
+        self.values = values;
+        self.back = vec![0.0; width * height];
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Spreads every amount towards the average of its four neighbors, then decays it.
+    /// Nothing flows over the edges, so without decay the total amount stays the same.
+    pub fn diffuse(&mut self) {
+        let (w, h) = (self.width, self.height);
+        let keep = 1.0 - self.decay;
+        for y in 0..h {
+            for x in 0..w {
+                let i = y * w + x;
+                let v = self.values[i];
+                // Neighbors past the edge count as the cell itself, so nothing flows that way.
+                let left = if x > 0 { self.values[i - 1] } else { v };
+                let right = if x + 1 < w { self.values[i + 1] } else { v };
+                let up = if y > 0 { self.values[i - w] } else { v };
+                let down = if y + 1 < h { self.values[i + w] } else { v };
+                let average = (left + right + up + down) / 4.0;
+                self.back[i] = (v + self.diffusion * (average - v)) * keep;
+            }
+        }
+        std::mem::swap(&mut self.values, &mut self.back);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Field;
+
+    #[test]
+    fn spreads_and_decays() {
+        let mut f = Field::new(String::from("pher"), 0.5, 0.0, 5, 5);
+        assert!(f.add(2, 2, 8.0));
+        assert!(!f.add(5, 0, 1.0));
+        f.diffuse();
+
+        assert_eq!(f.get(2, 2), 4.0);
+        assert_eq!(f.get(1, 2), 1.0);
+        assert_eq!(f.get(1, 1), 0.0);
+        for _ in 0..20 {
+            f.diffuse();
+        }
+        let total:f32 = f.values().iter().sum();
+        assert!((total - 8.0).abs() < 1e-4);
+
+        f.decay = 0.5;
+        f.diffuse();
+        let total:f32 = f.values().iter().sum();
+        assert!((total - 4.0).abs() < 1e-4);
+        assert_eq!(f.get(-1, 0), 0.0);
+    }
+
+    #[test]
+    fn changes_undo_the_last_diffuse() {
+        let mut f = Field::new(String::from("pher"), 0.5, 0.0, 10, 10);
+        f.add(2, 2, 8.0);
+        let before = f.values().to_vec();
+        f.diffuse();
+        f.add(9, 9, 1.0);
+
+        // Only the cells around the amount changed, plus the deposit after diffusing.
+        let changes = f.changes();
+        assert_eq!(changes.len(), 6);
+        f.restore(changes);
+        assert_eq!(f.values(), &before[..]);
+    }
+}
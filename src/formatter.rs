@@ -0,0 +1,341 @@
+use std::io;
+
+use crate::tokenizer::{Token, TokenType, Tokenizer};
+
+/// Number of spaces placed between the end of the longest line of code in a section and its trailing comments.
+const COMMENT_GAP:usize = 4;
+
+/// The part of a source file a line belongs to. Columns are aligned across all lines of the same section.
+#[derive(Clone, Copy, PartialEq)]
+enum Section {
+    Header,     // Label lines, such as 'states' and 'render'
+    Rules,
+    Render,
+    Seed,
+    World,
+    Layer
+}
+
+/// A single formatted source line: its code split into columns and an optional trailing comment.
+struct Line {
+    section:Section,
+    columns:Vec<String>,
+    comment:Option<String>
+}
+
+/// Tokenizes a cell-machine source string and prints it back in the canonical layout.
+pub fn format_source(source:String) -> io::Result<String> {
+    let mut t = Tokenizer::new(source);
+    t.start()?;
+    Ok(format_tokens(&t.tokens))
+}
+
+/// Prints a token stream in the canonical layout.
+/// Rule and render columns are aligned, whitespace is normalized, comments are kept
+/// and runs of blank lines are collapsed into one.
+pub fn format_tokens(tokens:&[Token]) -> String {
+    let lines = split_lines(tokens);
+
+    // Find the width of every column and of the code as a whole for each section.
+    let mut widths:Vec<(Section, Vec<usize>)> = vec![];
+    for line in lines.iter().flatten() {
+        let index = match widths.iter().position(|w| w.0 == line.section) {
+            Some(i) => i,
+            None => {
+                widths.push((line.section, vec![]));
+                widths.len() - 1
+            }
+        };
+        let w = &mut widths[index].1;
+        for (i, col) in line.columns.iter().enumerate() {
+            if i == w.len() {
+                w.push(0);
+            }
+            w[i] = w[i].max(col.chars().count());
+        }
+    }
+
+    let mut code_lines:Vec<Option<String>> = vec![];
+    let mut code_width:Vec<(Section, usize)> = vec![];
+    for line in lines.iter() {
+        match line {
+            Some(l) => {
+                // Label lines are not aligned with each other.
+                if l.section == Section::Header {
+                    code_lines.push(Some(l.columns.join(" ")));
+                    continue;
+                }
+                let w = &widths.iter().find(|w| w.0 == l.section).unwrap().1;
+                let code = align_columns(&l.columns, w);
+                match code_width.iter_mut().find(|c| c.0 == l.section) {
+                    Some(c) => c.1 = c.1.max(code.chars().count()),
+                    None => code_width.push((l.section, code.chars().count()))
+                };
+                code_lines.push(Some(code));
+            },
+            None => code_lines.push(None)
+        };
+    }
+
+    let mut out = String::new();
+    let mut pending_blank = false;
+    for (line, code) in lines.iter().zip(code_lines.iter()) {
+        let text = match (line, code) {
+            (Some(l), Some(c)) => {
+                if let Some(comment) = &l.comment {
+                    if c.is_empty() {
+                        comment.to_string()
+                    } else {
+                        let width = match code_width.iter().find(|w| w.0 == l.section) {
+                            Some(w) => w.1,
+                            None => c.chars().count()
+                        };
+                        format!("{:w$}{}", c, comment, w = width + COMMENT_GAP)
+                    }
+                } else {
+                    c.to_string()
+                }
+            },
+            _ => String::new()
+        };
+
+        if text.is_empty() {
+            pending_blank = !out.is_empty();
+            continue;
+        }
+        if pending_blank {
+            out.push('\n');
+            pending_blank = false;
+        }
+        out.push_str(&text);
+        out.push('\n');
+    }
+    out
+}
+
+/// Pads every column but the last to the width of its column in the section.
+fn align_columns(columns:&[String], widths:&[usize]) -> String {
+    let mut code = String::new();
+    for (i, col) in columns.iter().enumerate() {
+        if i == columns.len() - 1 {
+            code.push_str(col);
+        } else {
+            code.push_str(&format!("{:w$} ", col, w = widths[i]));
+        }
+    }
+    code
+}
+
+/// Breaks the token stream into lines. Blank lines are returned as None.
+fn split_lines(tokens:&[Token]) -> Vec<Option<Line>> {
+    let mut lines = vec![];
+    let mut section = Section::Rules;
+    let mut cur:Vec<&Token> = vec![];
+
+    for t in tokens.iter() {
+        match t.ttype {
+            TokenType::Space | TokenType::Tab => (),
+            TokenType::Newline | TokenType::EOF => {
+                lines.push(build_line(&cur, &mut section));
+                cur.clear();
+            },
+            _ => cur.push(t)
+        };
+    }
+    if !cur.is_empty() {
+        lines.push(build_line(&cur, &mut section));
+    }
+    lines
+}
+
+/// Builds a formatted line out of the tokens found on a single source line.
+/// Keeps track of which section of the file the tokens are in.
+fn build_line(tokens:&[&Token], section:&mut Section) -> Option<Line> {
+    if tokens.is_empty() {
+        return None;
+    }
+
+    let mut code:Vec<&Token> = tokens.to_vec();
+    let mut comment = None;
+    if code[code.len() - 1].ttype == TokenType::Comment {
+        let text = code.pop().unwrap().lexeme.trim_end().to_string();
+        if text.is_empty() || text.starts_with(char::is_whitespace) {
+            comment = Some(format!("#{}", text));
+        } else {
+            comment = Some(format!("# {}", text));
+        }
+    }
+
+    let mut line_section = *section;
+    let mut columns:Vec<String> = vec![];
+    if !code.is_empty() && code[0].ttype == TokenType::Label {
+        line_section = Section::Header;
+        *section = match code[0].lexeme.as_ref() {
+            "render" => Section::Render,
+            "seed" => Section::Seed,
+            "world" => Section::World,
+            "layer" => Section::Layer,
+            _ => Section::Rules
+        };
+        columns = code.iter().map(|t| lexeme(t)).collect();
+    }
+    else if !code.is_empty() && *section == Section::Rules {
+        columns.push(lexeme(code[0]));
+        let mut i = 1;
+
+        // Glue the neighbor clause (e.g. ^4.1 or 4.1) back into a single column.
+        let clause = if i < code.len() && code[i].ttype == TokenType::Number {
+            i += 1;
+            let mut clause = lexeme(code[i - 1]);
+            if i < code.len() && code[i].ttype == TokenType::Dot {
+                clause.push_str(&glue_clause(&code, &mut i));
+            }
+            clause
+        } else {
+            glue_clause(&code, &mut i)
+        };
+        if !clause.is_empty() {
+            columns.push(clause);
+        }
+
+        // Offspring, move and next state.
+        let end = code.len().min(i + 3);
+        columns.extend(code[i..end].iter().map(|t| lexeme(t)));
+        i = end;
+
+        // Each layer condition (e.g. terrain ^2.water or terrain .soil) and field term (e.g. field(pher)>0.5) is a single column too.
+        while i < code.len() {
+            if code[i].lexeme == "field" && code.get(i + 1).is_some_and(|t| t.ttype == TokenType::LParen) {
+                columns.push(glue_field(&code, &mut i));
+                continue;
+            }
+            // Always one space between the layer name and its clause, whatever the clause is.
+            let mut condition = lexeme(code[i]);
+            i += 1;
+            condition.push(' ');
+            condition.push_str(&glue_clause(&code, &mut i));
+            columns.push(condition);
+        }
+    }
+    else if *section == Section::World {
+        // Decimals (e.g. 0.25) are a number, a dot and a number.
+        let mut i = 0;
+        while i < code.len() {
+            columns.push(glue_decimal(&code, &mut i));
+        }
+    }
+    else {
+        columns = code.iter().map(|t| lexeme(t)).collect();
+    }
+
+    Some(Line { section:line_section, columns, comment })
+}
+
+/// Joins the tokens of a clause such as ^4.1 or *.0, up to and including the state after the dot.
+fn glue_clause(code:&[&Token], i:&mut usize) -> String {
+    let mut clause = String::new();
+    while *i < code.len() {
+        clause.push_str(&lexeme(code[*i]));
+        *i += 1;
+        if code[*i - 1].ttype == TokenType::Dot {
+            if *i < code.len() {
+                clause.push_str(&lexeme(code[*i]));
+                *i += 1;
+            }
+            break;
+        }
+    }
+    clause
+}
+
+/// Joins the tokens of a field term, from the field keyword up to and including its amount.
+fn glue_field(code:&[&Token], i:&mut usize) -> String {
+    let mut term = String::new();
+    let end = (*i + 6).min(code.len());
+    for t in code[*i..end].iter() {
+        term.push_str(&lexeme(t));
+    }
+    *i = end;
+    term.push_str(&glue_decimal_rest(code, i));
+    term
+}
+
+/// Returns the token at i, joined with a dot and number after it if it is the whole part of a decimal.
+fn glue_decimal(code:&[&Token], i:&mut usize) -> String {
+    let mut text = lexeme(code[*i]);
+    *i += 1;
+    if code[*i - 1].ttype == TokenType::Number {
+        text.push_str(&glue_decimal_rest(code, i));
+    }
+    text
+}
+
+/// Returns the fraction of a decimal starting at i, such as .25, or nothing if there is none.
+fn glue_decimal_rest(code:&[&Token], i:&mut usize) -> String {
+    if *i + 1 < code.len() && code[*i].ttype == TokenType::Dot && code[*i + 1].ttype == TokenType::Number {
+        *i += 2;
+        return format!(".{}", lexeme(code[*i - 1]));
+    }
+    String::new()
+}
+
+/// Returns the source text for a token.
+fn lexeme(t:&Token) -> String {
+    match t.ttype {
+        TokenType::Null => String::from("_"),
+        _ => t.lexeme.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_source;
+
+    #[test]
+    fn aligns_columns_and_comments() {
+        let src = "\n\nstates 3\n# rules\n1\t=1.1 2 _ 2 # under\n1 ^4.1   2 _ 2\t# over\n\n\n2 =3.1 1 _ 1\nrender 16 40 40\n0 000000FF\n12 FF00FFAA   \n";
+        let expected = "states 3\n# rules\n1 =1.1 2 _ 2    # under\n1 ^4.1 2 _ 2    # over\n\n2 =3.1 1 _ 1\nrender 16 40 40\n0  000000FF\n12 FF00FFAA\n";
+
+        assert_eq!(format_source(src.to_string()).unwrap(), expected);
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let src = "states 4\n1 *.0 _ ^ 2\t# move\n1 ^.3 2 @ 1\n3 ^2.3 3 ^ 3 #again\n3 4 . 2 _ _ 1\n";
+        let once = format_source(src.to_string()).unwrap();
+
+        assert_eq!(format_source(once.clone()).unwrap(), once);
+        assert!(once.lines().last().unwrap().starts_with("3 4.2 "));
+    }
+
+    #[test]
+    fn keeps_layer_conditions_together() {
+        let src = "states 2\n1 ^0.0 _ r 1   terrain . soil  terrain ^ 2.wall\n1 *.0 _ _ 1 terrain=1.1\nlayer terrain 2 soil wall\nwall   404040\n0 #00FF0080\nseed terrain\n0 0 wall\n";
+        let expected = "states 2\n1 ^0.0 _ r 1 terrain .soil terrain ^2.wall\n1 *.0  _ _ 1 terrain =1.1\nlayer terrain 2 soil wall\nwall 404040\n0    #00FF0080\nseed terrain\n0 0 wall\n";
+
+        assert_eq!(format_source(src.to_string()).unwrap(), expected);
+        assert_eq!(format_source(expected.to_string()).unwrap(), expected);
+    }
+
+    #[test]
+    fn keeps_field_terms_and_decimals_together() {
+        let src = "states 2\n1 ^0.0 _ r 1 field ( pher ) > 0 . 5   field(pher)+2\nworld\nfield pher 0 . 25 0.05\n";
+        let expected = "states 2\n1 ^0.0 _ r 1 field(pher)>0.5 field(pher)+2\nworld\nfield pher 0.25 0.05\n";
+
+        assert_eq!(format_source(src.to_string()).unwrap(), expected);
+        assert_eq!(format_source(expected.to_string()).unwrap(), expected);
+    }
+
+    #[test]
+    fn examples_are_formatted() {
+        let dir = format!("{}/examples", env!("CARGO_MANIFEST_DIR"));
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            // test.cell sketches syntax that is not implemented yet, so it does not parse.
+            if path.extension().is_some_and(|e| e == "cell") && !path.ends_with("test.cell") {
+                let src = std::fs::read_to_string(&path).unwrap();
+                assert_eq!(format_source(src.clone()).unwrap(), src, "{} is not formatted", path.display());
+            }
+        }
+    }
+}
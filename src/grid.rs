@@ -0,0 +1,190 @@
+use std::fmt::Debug;
+
+/// A type that cell states can be stored as.
+/// Smaller types keep more of the grid in cache, so use the smallest one that fits every state.
+pub trait CellState: Copy + Default + PartialEq + Debug + Send + Sync + 'static {
+    /// Number of states the type can hold.
+    const MAX_STATES:usize;
+
+    fn from_i32(v:i32) -> Self;
+    fn to_i32(self) -> i32;
+}
+
+impl CellState for u8 {
+    const MAX_STATES:usize = 1 << 8;
+
+    fn from_i32(v:i32) -> u8 {
+        v as u8
+    }
+
+    fn to_i32(self) -> i32 {
+        self as i32
+    }
+}
+
+impl CellState for u16 {
+    const MAX_STATES:usize = 1 << 16;
+
+    fn from_i32(v:i32) -> u16 {
+        v as u16
+    }
+
+    fn to_i32(self) -> i32 {
+        self as i32
+    }
+}
+
+/// Cell states stored row by row in one contiguous buffer.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Grid<S:CellState> {
+    width:usize,
+    height:usize,
+    cells:Vec<S>
+}
+
+impl<S:CellState> Grid<S> {
+    /// Creates a grid with every cell in state 0.
+    pub fn new(width:usize, height:usize) -> Grid<S> {
+        Grid { width, height, cells:vec![S::default(); width * height] }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Position of a cell in the buffer.
+    pub fn index(&self, x:usize, y:usize) -> usize {
+        y * self.width + x
+    }
+
+    pub fn get(&self, x:usize, y:usize) -> S {
+        self.cells[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x:usize, y:usize, val:S) {
+        self.cells[y * self.width + x] = val;
+    }
+
+    /// Every cell, in row-major order.
+    pub fn cells(&self) -> &[S] {
+        &self.cells
+    }
+
+    /// Copies another grid of the same size into this one without reallocating.
+    pub fn copy_from(&mut self, other:&Grid<S>) {
+        self.cells.copy_from_slice(&other.cells);
+    }
+}
+
+/// The states of a rectangle of cells at one generation, as produced by the simulation engines.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Snapshot {
+    pub width:usize,
+    pub height:usize,
+    pub generation:u64,
+    pub origin:(i64, i64),  // World position of the top left cell
+    pub cells:Vec<i32>      // Row-major states
+}
+
+impl Snapshot {
+    pub fn get(&self, x:usize, y:usize) -> i32 {
+        self.cells[y * self.width + x]
+    }
+}
+
+/// A fixed size set of indices, one bit per index.
+#[derive(Clone, PartialEq, Debug)]
+pub struct BitSet {
+    words:Vec<u64>,
+    len:usize
+}
+
+impl BitSet {
+    /// Creates an empty set that can hold the indices 0 to len - 1.
+    pub fn new(len:usize) -> BitSet {
+        BitSet { words:vec![0; len.div_ceil(64)], len }
+    }
+
+    /// Number of indices the set can hold.
+    pub fn capacity(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no index is in the set.
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|w| *w == 0)
+    }
+
+    pub fn insert(&mut self, i:usize) {
+        self.words[i / 64] |= 1 << (i % 64);
+    }
+
+    pub fn remove(&mut self, i:usize) {
+        self.words[i / 64] &= !(1 << (i % 64));
+    }
+
+    pub fn contains(&self, i:usize) -> bool {
+        self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    pub fn clear(&mut self) {
+        self.words.iter_mut().for_each(|w| *w = 0);
+    }
+
+    /// Number of indices in the set.
+    pub fn count(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Copies another set of the same size into this one without reallocating.
+    pub fn copy_from(&mut self, other:&BitSet) {
+        self.words.copy_from_slice(&other.words);
+    }
+
+    /// Every index in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, w)| {
+            let mut word = *w;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(i * 64 + bit)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BitSet, Grid};
+
+    #[test]
+    fn grid_is_row_major() {
+        let mut grid:Grid<u8> = Grid::new(3, 2);
+        grid.set(2, 1, 7);
+
+        assert_eq!(grid.index(2, 1), 5);
+        assert_eq!(grid.cells()[5], 7);
+        assert_eq!(grid.get(2, 1), 7);
+    }
+
+    #[test]
+    fn bitset_iterates_in_order() {
+        let mut set = BitSet::new(200);
+        for i in [130, 3, 64, 199, 63] {
+            set.insert(i);
+        }
+        set.remove(64);
+
+        assert_eq!(set.iter().collect::<Vec<usize>>(), vec![3, 63, 130, 199]);
+        assert_eq!(set.count(), 4);
+        assert!(set.contains(199) && !set.contains(64));
+    }
+}
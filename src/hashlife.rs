@@ -0,0 +1,409 @@
+use std::{collections::HashMap, io};
+
+use crate::{bio::{RuleSet, RuleTable, MAX_COUNTED_STATES}, config::RenderRules, grid::Snapshot};
+
+type NodeId = u32;
+
+/// Highest level the root can reach, so that the width of every square fits in an i64.
+const MAX_LEVEL:u8 = 62;
+
+/// A square of 2^level by 2^level cells. Level 0 nodes are single cells.
+#[derive(Clone, Copy)]
+struct Node {
+    level:u8,
+    children:[NodeId; 4],   // nw, ne, sw, se
+    state:i32               // State of the cell, for level 0 nodes
+}
+
+/// Runs a rule set with the HashLife algorithm, on a plane that has no edges.
+/// The plane is a quadtree whose identical squares are stored once, and the future of every square is
+/// remembered, so patterns that repeat themselves can be advanced by millions of generations at a time.
+///
+/// Only rule sets that can be compiled into a RuleTable and never move a cell can be run, since a cell's next state
+/// must only depend on the states around it. Cells that were never set are in the background state, which must
+/// not change when every neighbor is also in the background state.
+/// Steps are synchronous, so the results match a synchronous Processor as long as the pattern stays away from its edges.
+pub struct HashLife {
+    rule_set:RuleSet,
+    table:RuleTable,
+    background:i32,
+    nodes:Vec<Node>,
+    leaves:HashMap<i32, NodeId>,
+    branches:HashMap<[NodeId; 4], NodeId>,
+    empty:Vec<NodeId>,                      // Squares of background cells, by level
+    results:HashMap<(NodeId, u8), NodeId>,  // Center of a square after 2^j generations, by square and j
+    root:NodeId,
+    origin:(i64, i64),                      // Position of the top left cell of the root
+    pub generation:u64
+}
+
+impl HashLife {
+    /// Creates an engine with every cell in the background state, then places the seed points of the render rules.
+    pub fn new(rule_set:RuleSet, render_rules:&RenderRules, background:i32) -> io::Result<HashLife> {
+        let table = match rule_set.compile() {
+            Some(t) => t,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "HashLife can only run rule sets made of neighbor count conditions."))
+        };
+        for state in 1..rule_set.nstates {
+            if let Some(rules) = rule_set.state_rules(state) {
+                if rules.iter().any(|r| r.move_to.is_random || "ruld".contains(r.move_to.constant)) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "HashLife can only run rules that do not move cells."));
+                }
+            }
+        }
+
+        let mut h = HashLife { rule_set, table, background, nodes:vec![], leaves:HashMap::new(), branches:HashMap::new(), empty:vec![],
+            results:HashMap::new(), root:0, origin:(0, 0), generation:0 };
+        if h.next_state(background, &[background; 8]) != background {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("The background state {} changes when surrounded by itself.", background)));
+        }
+        h.root = h.empty_node(3);
+        for p in render_rules.get_seed().iter() {
+            h.set_cell(p.x as i64, p.y as i64, p.state);
+        }
+        Ok(h)
+    }
+
+    /// Returns the state of the cell at (x, y).
+    pub fn get_cell(&self, x:i64, y:i64) -> i32 {
+        let mut id = self.root;
+        let (mut x, mut y) = (x - self.origin.0, y - self.origin.1);
+        let size = 1i64 << self.nodes[id as usize].level;
+        if x < 0 || y < 0 || x >= size || y >= size {
+            return self.background;
+        }
+        loop {
+            let node = self.nodes[id as usize];
+            if node.level == 0 {
+                return node.state;
+            }
+            let half = 1i64 << (node.level - 1);
+            let quadrant = (x >= half) as usize + 2 * (y >= half) as usize;
+            id = node.children[quadrant];
+            x %= half;
+            y %= half;
+        }
+    }
+
+    /// Sets the cell at (x, y), growing the tree until it covers the cell.
+    pub fn set_cell(&mut self, x:i64, y:i64, state:i32) {
+        loop {
+            let size = 1i64 << self.nodes[self.root as usize].level;
+            let (rx, ry) = (x - self.origin.0, y - self.origin.1);
+            if rx >= 0 && ry >= 0 && rx < size && ry < size {
+                self.root = self.set_in(self.root, rx, ry, state);
+                return;
+            }
+            self.expand();
+        }
+    }
+
+    /// Copies the cells of a snapshot in, at the snapshot's origin, and takes on its generation.
+    pub fn load(&mut self, snapshot:&Snapshot) {
+        let (ox, oy) = snapshot.origin;
+        for y in 0..snapshot.height {
+            for x in 0..snapshot.width {
+                self.set_cell(ox + x as i64, oy + y as i64, snapshot.get(x, y));
+            }
+        }
+        self.generation = snapshot.generation;
+    }
+
+    /// Copies the cells from (0, 0) to (width - 1, height - 1) out of the engine.
+    pub fn snapshot(&self, width:usize, height:usize) -> Snapshot {
+        let mut cells = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                cells.push(self.get_cell(x as i64, y as i64));
+            }
+        }
+        Snapshot { width, height, generation:self.generation, origin:(0, 0), cells }
+    }
+
+    /// Advances by 2^k generations.
+    /// Fails if the plane it takes is too large to address, which is the case from k = 59 on, or for patterns spread far apart.
+    pub fn step_pow2(&mut self, k:u8) -> io::Result<()> {
+        let too_large = || io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot advance by 2^{} generations, the plane would be too large.", k));
+        if k > MAX_LEVEL - 4 {
+            return Err(too_large());
+        }
+        let generation = match self.generation.checked_add(1 << k) {
+            Some(g) => g,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot advance by 2^{} generations past generation {}.", k, self.generation)))
+        };
+        // The pattern must sit in the middle half of a root big enough that it cannot
+        // grow out of the middle of the next, twice as large, root within 2^k generations.
+        while self.nodes[self.root as usize].level < k + 3 || !self.is_padded() {
+            if self.nodes[self.root as usize].level >= MAX_LEVEL - 1 {
+                return Err(too_large());
+            }
+            self.expand();
+        }
+        self.expand();
+        let size = 1i64 << self.nodes[self.root as usize].level;
+        self.root = self.step(self.root, k);
+        self.origin = (self.origin.0 + size / 4, self.origin.1 + size / 4);
+        self.generation = generation;
+        Ok(())
+    }
+
+    /// Advances by any number of generations, in steps of powers of two.
+    /// Fails before advancing at all if one of the steps would be too large, see step_pow2.
+    pub fn advance(&mut self, generations:u64) -> io::Result<()> {
+        if generations >> (MAX_LEVEL - 3) != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot advance by {} generations at once.", generations)));
+        }
+        for k in 0..MAX_LEVEL - 3 {
+            if generations & (1 << k) != 0 {
+                self.step_pow2(k)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of distinct squares stored.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The state a cell moves to, given its neighbors in the order of the processor.
+    fn next_state(&self, state:i32, neighbors:&[i32; 8]) -> i32 {
+        if state == 0 || self.rule_set.state_rules(state as usize).is_none() {
+            return state;
+        }
+        let mut counts = [0; MAX_COUNTED_STATES];
+        let counted = self.table.counted(state as usize);
+        for (k, s) in counted.iter().enumerate() {
+            counts[k] = neighbors.iter().filter(|n| *n == s).count();
+        }
+        match self.table.lookup(state as usize, &counts[..counted.len()]) {
+            Some(i) => self.rule_set.state_rules(state as usize).unwrap()[i].next_state,
+            None => state
+        }
+    }
+
+    fn leaf(&mut self, state:i32) -> NodeId {
+        if let Some(id) = self.leaves.get(&state) {
+            return *id;
+        }
+        let id = self.nodes.len() as NodeId;
+        self.nodes.push(Node { level:0, children:[0; 4], state });
+        self.leaves.insert(state, id);
+        id
+    }
+
+    /// Returns the square made of four squares one level down, storing it if it is new.
+    fn join(&mut self, nw:NodeId, ne:NodeId, sw:NodeId, se:NodeId) -> NodeId {
+        let children = [nw, ne, sw, se];
+        if let Some(id) = self.branches.get(&children) {
+            return *id;
+        }
+        let id = self.nodes.len() as NodeId;
+        self.nodes.push(Node { level:self.nodes[nw as usize].level + 1, children, state:0 });
+        self.branches.insert(children, id);
+        id
+    }
+
+    fn empty_node(&mut self, level:u8) -> NodeId {
+        while self.empty.len() <= level as usize {
+            let id = match self.empty.last() {
+                Some(e) => self.join(*e, *e, *e, *e),
+                None => self.leaf(self.background)
+            };
+            self.empty.push(id);
+        }
+        self.empty[level as usize]
+    }
+
+    fn children(&self, id:NodeId) -> [NodeId; 4] {
+        self.nodes[id as usize].children
+    }
+
+    fn set_in(&mut self, id:NodeId, x:i64, y:i64, state:i32) -> NodeId {
+        let node = self.nodes[id as usize];
+        if node.level == 0 {
+            return self.leaf(state);
+        }
+        let half = 1i64 << (node.level - 1);
+        let quadrant = (x >= half) as usize + 2 * (y >= half) as usize;
+        let mut c = node.children;
+        c[quadrant] = self.set_in(c[quadrant], x % half, y % half, state);
+        self.join(c[0], c[1], c[2], c[3])
+    }
+
+    /// Doubles the size of the root, keeping the old root in the middle.
+    fn expand(&mut self) {
+        let level = self.nodes[self.root as usize].level;
+        let e = self.empty_node(level - 1);
+        let [nw, ne, sw, se] = self.children(self.root);
+        let nw = self.join(e, e, e, nw);
+        let ne = self.join(e, e, ne, e);
+        let sw = self.join(e, sw, e, e);
+        let se = self.join(se, e, e, e);
+        self.root = self.join(nw, ne, sw, se);
+        let half = 1i64 << level >> 1;
+        self.origin = (self.origin.0 - half, self.origin.1 - half);
+    }
+
+    /// Returns true if every cell outside of the middle half of the root is in the background state.
+    fn is_padded(&mut self) -> bool {
+        let level = self.nodes[self.root as usize].level;
+        let e = self.empty_node(level - 2);
+        let [nw, ne, sw, se] = self.children(self.root);
+        let (nw, ne, sw, se) = (self.children(nw), self.children(ne), self.children(sw), self.children(se));
+        [nw[0], nw[1], nw[2], ne[0], ne[1], ne[3], sw[0], sw[2], sw[3], se[1], se[2], se[3]].iter().all(|c| *c == e)
+    }
+
+    /// The middle half of a square.
+    fn center(&mut self, id:NodeId) -> NodeId {
+        let [nw, ne, sw, se] = self.children(id);
+        let (a, b, c, d) = (self.children(nw)[3], self.children(ne)[2], self.children(sw)[1], self.children(se)[0]);
+        self.join(a, b, c, d)
+    }
+
+    /// Returns the middle half of a square of level L after 2^j generations, with j at most L - 2.
+    fn step(&mut self, id:NodeId, j:u8) -> NodeId {
+        if let Some(r) = self.results.get(&(id, j)) {
+            return *r;
+        }
+        let level = self.nodes[id as usize].level;
+        let result = if level == 2 {
+            self.step_leaves(id)
+        }
+        else {
+            // Nine overlapping squares one level down.
+            let [a, b, c, d] = self.children(id);
+            let (ac, bc, cc, dc) = (self.children(a), self.children(b), self.children(c), self.children(d));
+            let n01 = self.join(ac[1], bc[0], ac[3], bc[2]);
+            let n10 = self.join(ac[2], ac[3], cc[0], cc[1]);
+            let n11 = self.join(ac[3], bc[2], cc[1], dc[0]);
+            let n12 = self.join(bc[2], bc[3], dc[0], dc[1]);
+            let n21 = self.join(cc[1], dc[0], cc[3], dc[2]);
+            let nine = [a, n01, b, n10, n11, n12, c, n21, d];
+
+            // At full speed both halves of the time are spent stepping, otherwise only the second.
+            let full = j == level - 2;
+            let mut r = [0; 9];
+            for (i, n) in nine.iter().enumerate() {
+                r[i] = if full { self.step(*n, level - 3) } else { self.center(*n) };
+            }
+            let q = [
+                self.join(r[0], r[1], r[3], r[4]),
+                self.join(r[1], r[2], r[4], r[5]),
+                self.join(r[3], r[4], r[6], r[7]),
+                self.join(r[4], r[5], r[7], r[8])
+            ];
+            let j = if full { level - 3 } else { j };
+            let s = q.map(|n| self.step(n, j));
+            self.join(s[0], s[1], s[2], s[3])
+        };
+        self.results.insert((id, j), result);
+        result
+    }
+
+    /// Runs one generation on a 4 by 4 square, returning the middle 2 by 2 square.
+    fn step_leaves(&mut self, id:NodeId) -> NodeId {
+        let mut cells = [[0; 4]; 4];
+        for (q, child) in self.children(id).iter().enumerate() {
+            for (k, leaf) in self.children(*child).iter().enumerate() {
+                let x = (q % 2) * 2 + k % 2;
+                let y = (q / 2) * 2 + k / 2;
+                cells[y][x] = self.nodes[*leaf as usize].state;
+            }
+        }
+        let mut next = [0; 4];
+        for (k, n) in next.iter_mut().enumerate() {
+            let (x, y) = (1 + k % 2, 1 + k / 2);
+            let neighbors = [
+                cells[y - 1][x - 1], cells[y - 1][x], cells[y - 1][x + 1], cells[y][x - 1],
+                cells[y][x + 1], cells[y + 1][x - 1], cells[y + 1][x], cells[y + 1][x + 1]
+            ];
+            *n = self.next_state(cells[y][x], &neighbors);
+        }
+        let leaves = next.map(|s| self.leaf(s));
+        self.join(leaves[0], leaves[1], leaves[2], leaves[3])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tokenizer::Tokenizer, parser::Parser, lower::{lower, Program}, config::UpdateMode, processor::Processor};
+    use super::HashLife;
+
+    const CONWAY:&str = "states 3\n1 =1.1 2 _ 2\n1 =0.1 2 _ 2\n1 =2.1 1 _ 1\n1 =3.1 1 _ 1\n1 ^4.1 2 _ 2\n2 =3.1 1 _ 1\n";
+
+    fn program_from(src:&str) -> Program {
+        let mut t = Tokenizer::new(src.to_string());
+        t.start().unwrap();
+        lower(&Parser::new(t.tokens).start().unwrap()).ok().unwrap()
+    }
+
+    #[test]
+    fn matches_synchronous_processor() {
+        // A glider and an R-pentomino on a grid of dead cells.
+        let src = format!("{}render 1 64 64\nseed\n{}", CONWAY,
+            "5 4 1\n6 5 1\n4 6 1\n5 6 1\n6 6 1\n33 30 1\n34 30 1\n32 31 1\n33 31 1\n33 32 1\n");
+        let program = program_from(&src);
+        let mut h = HashLife::new(program.rule_set.clone(), &program.render_rules, 2).unwrap();
+        let mut p:Processor = Processor::new_with_world(program.rule_set, program.render_rules, program.world);
+        p.world.update = UpdateMode::Synchronous;
+        for y in 0..64 {
+            for x in 0..64 {
+                if p.snapshot().get(x, y) == 0 {
+                    p.set_cell(2, x, y);
+                }
+            }
+        }
+        h.load(&p.snapshot());
+
+        for _ in 0..40 {
+            p.step();
+        }
+        h.advance(40).unwrap();
+        assert_eq!(h.snapshot(64, 64), p.snapshot());
+    }
+
+    #[test]
+    fn oscillators_survive_long_runs() {
+        let program = program_from(&format!("{}render 1 8 8\nseed\n3 2 1\n3 3 1\n3 4 1\n", CONWAY));
+        let mut h = HashLife::new(program.rule_set, &program.render_rules, 2).unwrap();
+        let start = h.snapshot(8, 8);
+
+        h.step_pow2(40).unwrap();
+        assert_eq!(h.generation, 1 << 40);
+        assert_eq!(h.snapshot(8, 8).cells, start.cells);
+        h.advance(1).unwrap();
+        assert_eq!(h.get_cell(3, 2), 2);
+        assert_eq!(h.get_cell(2, 3), 1);
+
+        // The largest step still fits, anything larger is refused without changing the pattern.
+        h.step_pow2(58).unwrap();
+        assert_eq!(h.generation, (1 << 40) + 1 + (1 << 58));
+        assert!(h.step_pow2(59).is_err());
+        assert!(h.advance(u64::MAX).is_err());
+        assert_eq!(h.generation, (1 << 40) + 1 + (1 << 58));
+        assert_eq!(h.get_cell(3, 2), 2);
+    }
+
+    #[test]
+    fn rejects_moving_rules() {
+        let program = program_from("states 2\n1 =0.1 _ r 1\n");
+        let error = HashLife::new(program.rule_set, &program.render_rules, 0).err().unwrap();
+        assert_eq!(error.to_string(), "HashLife can only run rules that do not move cells.");
+    }
+
+    #[test]
+    fn rejects_explicit_neighbor_rules() {
+        let program = program_from("states 2\n1 2 _ _ 0\n");
+        let error = HashLife::new(program.rule_set, &program.render_rules, 0).err().unwrap();
+        assert_eq!(error.to_string(), "HashLife can only run rule sets made of neighbor count conditions.");
+    }
+
+    #[test]
+    fn rejects_a_background_that_changes() {
+        // The background state 2 turns into 1 when every neighbor is also 2.
+        let program = program_from("states 3\n2 =8.2 _ _ 1\n");
+        let error = HashLife::new(program.rule_set, &program.render_rules, 2).err().unwrap();
+        assert_eq!(error.to_string(), "The background state 2 changes when surrounded by itself.");
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+
+use rand::rngs::StdRng;
+
+/// Most generations kept by default.
+pub const DEFAULT_HISTORY:usize = 100;
+/// Most changed cells kept over all generations, older generations are dropped to stay under it.
+pub const MAX_HISTORY_CELLS:usize = 1 << 21;
+
+/// Everything needed to undo one step.
+#[derive(Clone)]
+pub(crate) struct Change<S> {
+    pub generation:u64,                             // Generation before the step
+    pub cells:Vec<(usize, S)>,                      // Position in the grid and state before the step, of every cell the step changed
+    pub frame:Option<((i64, i64), usize, usize)>,   // Origin and size of the grid before the step, if the step moved it
+    pub fields:Vec<Vec<(usize, f32)>>,              // Index and amount before the step of every field amount the step changed
+    pub rand:StdRng                                 // Random number generator before the step
+}
+
+impl<S> Change<S> {
+    /// Cells stored by the change, counting every field amount as a cell.
+    fn size(&self) -> usize {
+        self.cells.len() + self.fields.iter().map(|f| f.len()).sum::<usize>()
+    }
+}
+
+/// The most recent steps of a processor, oldest first.
+/// Stores only the cells each step changed, and drops the oldest steps when it holds too many.
+#[derive(Clone)]
+pub(crate) struct History<S> {
+    changes:VecDeque<Change<S>>,
+    n_cells:usize,
+    limit:usize     // Most generations kept, 0 turns history off.
+}
+
+impl<S> History<S> {
+    pub fn new(limit:usize) -> History<S> {
+        History { changes:VecDeque::new(), n_cells:0, limit }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Changes how many generations are kept, dropping the oldest ones that no longer fit.
+    pub fn set_limit(&mut self, limit:usize) {
+        self.limit = limit;
+        self.trim();
+    }
+
+    pub fn push(&mut self, change:Change<S>) {
+        self.n_cells += change.size();
+        self.changes.push_back(change);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.changes.len() > self.limit || (self.n_cells > MAX_HISTORY_CELLS && self.changes.len() > 1) {
+            let dropped = self.changes.pop_front().unwrap();
+            self.n_cells -= dropped.size();
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Change<S>> {
+        let change = self.changes.pop_back()?;
+        self.n_cells -= change.size();
+        Some(change)
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// The oldest generation that can be gone back to.
+    pub fn oldest(&self) -> Option<u64> {
+        self.changes.front().map(|c| c.generation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+    use super::{Change, History, MAX_HISTORY_CELLS};
+
+    fn change(generation:u64, n_cells:usize) -> Change<u8> {
+        Change { generation, cells:vec![(0, 1); n_cells], frame:None, fields:vec![], rand:StdRng::seed_from_u64(0) }
+    }
+
+    #[test]
+    fn drops_the_oldest_steps() {
+        let mut history = History::new(3);
+        for g in 0..5 {
+            history.push(change(g, 1));
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.oldest(), Some(2));
+        assert_eq!(history.pop().unwrap().generation, 4);
+
+        // Large steps are dropped to stay under the cell budget, but the latest one is always kept.
+        history.push(change(5, MAX_HISTORY_CELLS));
+        assert_eq!(history.len(), 1);
+        history.push(change(6, MAX_HISTORY_CELLS + 1));
+        assert_eq!(history.oldest(), Some(6));
+    }
+}
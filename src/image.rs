@@ -0,0 +1,60 @@
+use std::io::{self, Write};
+
+use crate::config::RenderRules;
+
+/// Writes rows of cells as a binary PPM image, each cell a square of the render cell size.
+/// Cells are drawn in their state's color over a white background, so dead cells and states without a color are white.
+/// Rows come from a snapshot of the grid, or from the space-time diagram of a one-dimensional world.
+pub fn write_ppm<W:Write>(out:W, rows:&[Vec<i32>], render_rules:&RenderRules) -> io::Result<()> {
+    let mut out = out;
+    let size = render_rules.cell_size.max(1);
+    let width = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    write!(out, "P6\n{} {}\n255\n", width * size, rows.len() * size)?;
+
+    let mut line = Vec::with_capacity(width * size * 3);
+    for row in rows.iter() {
+        line.clear();
+        for x in 0..width {
+            let state = row.get(x).copied().unwrap_or(0);
+            let pixel = if state == 0 { [255; 3] } else { blend(render_rules.get_colors().get(&state).copied()) };
+            for _ in 0..size {
+                line.extend_from_slice(&pixel);
+            }
+        }
+        for _ in 0..size {
+            out.write_all(&line)?;
+        }
+    }
+    out.flush()
+}
+
+/// Mixes an RGBA color into the white background by its alpha.
+fn blend(color:Option<u32>) -> [u8; 3] {
+    let [r, g, b, a] = color.unwrap_or(0xFFFFFF00).to_be_bytes();
+    let mix = |c:u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+    [mix(r), mix(g), mix(b)]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::RenderRules;
+    use super::write_ppm;
+
+    #[test]
+    fn writes_cells_as_squares() {
+        let mut render_rules = RenderRules::new_blank();
+        render_rules.cell_size = 2;
+        render_rules.set_color(1, 0x000000FF);
+        render_rules.set_color(2, 0xFF000080);
+        let mut out = vec![];
+        write_ppm(&mut out, &[vec![1, 0], vec![2]], &render_rules).unwrap();
+
+        let header = b"P6\n4 4\n255\n";
+        assert_eq!(&out[..header.len()], header);
+        let pixels = &out[header.len()..];
+        assert_eq!(pixels.len(), 4 * 4 * 3);
+        assert_eq!(&pixels[0..12], &[0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 255, 255]);
+        assert_eq!(&pixels[24..30], &[255, 127, 127, 255, 127, 127]);
+        assert_eq!(&pixels[30..36], &[255; 6]);
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use crate::grid::Grid;
+
+/// Most states a layer can have.
+pub const MAX_LAYER_STATES:usize = 256;
+
+/// A grid of static cells under the main grid, such as terrain.
+/// Layers have their own states and are never changed by a step, rules read them through layer checks.
+/// Cells are at world positions, starting at (0, 0) and the size of the render grid. Cells outside of it read as state 0.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Layer {
+    pub name:String,
+    pub nstates:usize,
+    colors:HashMap<i32, u32>,
+    grid:Grid<u8>
+}
+
+impl Layer {
+    pub fn new(name:String, nstates:usize, width:usize, height:usize) -> Layer {
+        Layer { name, nstates, colors:HashMap::new(), grid:Grid::new(width, height) }
+    }
+
+    pub fn width(&self) -> usize {
+        self.grid.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.grid.height()
+    }
+
+    /// State of the cell at world position (x, y), 0 outside of the layer.
+    pub fn get(&self, x:i64, y:i64) -> i32 {
+        if x < 0 || y < 0 || x >= self.grid.width() as i64 || y >= self.grid.height() as i64 {
+            return 0;
+        }
+        self.grid.get(x as usize, y as usize) as i32
+    }
+
+    /// Sets the cell at world position (x, y). Returns false if it is outside of the layer or the state is not one of the layer's.
+    pub fn set(&mut self, x:usize, y:usize, state:i32) -> bool {
+        if x >= self.grid.width() || y >= self.grid.height() || state < 0 || state as usize >= self.nstates {
+            return false;
+        }
+        self.grid.set(x, y, state as u8);
+        true
+    }
+
+    /// Changes the size of the layer, keeping the cells that still fit.
+    pub fn resize(&mut self, width:usize, height:usize) {
+        let mut grid = Grid::new(width, height);
+        for y in 0..height.min(self.grid.height()) {
+            for x in 0..width.min(self.grid.width()) {
+                grid.set(x, y, self.grid.get(x, y));
+            }
+        }
+        self.grid = grid;
+    }
+
+    /// Color a state is drawn with, if it has one. States without a color are not drawn.
+    pub fn get_color(&self, state:i32) -> Option<u32> {
+        self.colors.get(&state).copied()
+    }
+
+    pub fn set_color(&mut self, state:i32, color:u32) {
+        self.colors.insert(state, color);
+    }
+
+    /// Every cell of the layer, as (x, y, state) in row-major order.
+    pub fn cells(&self) -> impl Iterator<Item = (usize, usize, i32)> + '_ {
+        let w = self.grid.width();
+        self.grid.cells().iter().enumerate().map(move |(i, s)| (i % w, i / w, *s as i32))
+    }
+}
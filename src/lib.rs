@@ -1,7 +1,23 @@
 pub mod tokenizer;
 pub mod parser;
+pub mod ast;
+pub mod lower;
 pub mod bio;
+pub mod grid;
+pub mod layer;
+pub mod field;
 pub mod processor;
-pub(crate) mod config;
+pub mod hashlife;
+pub mod history;
+pub mod detect;
+pub mod stats;
+pub mod observer;
+pub mod trace;
+pub mod image;
+pub mod config;
+#[cfg(feature = "gui")]
 pub mod simple_renderer;
-pub mod cli;
\ No newline at end of file
+pub mod cli;
+pub mod formatter;
+pub mod lsp;
+pub mod check;
\ No newline at end of file
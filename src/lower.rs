@@ -0,0 +1,477 @@
+use std::fmt;
+
+use crate::{ast::*, bio::{BioRule, BioMove, Deposit, FieldCheck, LayerCheck, LayerNeighbors, RuleSet}, config::{BlockedMove, Bounds, FieldConfig, RenderRules, StatePoint, UpdateMode, WorldConfig}, grid::CellState, layer::{Layer, MAX_LAYER_STATES}};
+
+/// A rule or setting that is syntactically valid but cannot be simulated.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SemanticError {
+    pub msg:String,
+    pub span:Span
+}
+
+impl fmt::Display for SemanticError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Error on line {}: {}", self.span, self.msg)
+    }
+}
+
+/// Everything the processor needs to run a system.
+pub struct Program {
+    pub rule_set:RuleSet,
+    pub render_rules:RenderRules,
+    pub world:WorldConfig
+}
+
+/// Turns a syntax tree into a RuleSet, RenderRules and WorldConfig.
+/// Every problem found is reported, not only the first.
+pub fn lower(system:&System) -> Result<Program, Vec<SemanticError>> {
+    let mut errors = vec![];
+
+    let n_states = match &system.header {
+        Some(h) => {
+            if h.n_states.value < 1 {
+                errors.push(SemanticError { msg:String::from("A system needs at least 1 state."), span:h.n_states.span });
+            }
+            if h.n_states.value as usize > u16::MAX_STATES {
+                errors.push(SemanticError { msg:format!("A system can have at most {} states.", u16::MAX_STATES), span:h.n_states.span });
+            }
+            if h.names.len() > h.n_states.value.max(0) as usize {
+                errors.push(SemanticError { msg:format!("{} state names given for a system with {} states.", h.names.len(), h.n_states.value), span:h.span });
+            }
+            h.n_states.value
+        },
+        None => {
+            errors.push(SemanticError { msg:String::from("Missing 'states' header."), span:Span::new(1, 1, 1) });
+            0
+        }
+    };
+    // Checks that a state written in the source exists in the system.
+    let check_state = |n:&Number, errors:&mut Vec<SemanticError>| {
+        if n.value < 0 || n.value >= n_states {
+            errors.push(SemanticError { msg:format!("State {} is out of range for a system with {} states.", n.value, n_states), span:n.span });
+        }
+    };
+
+    // Fields are declared in the world section, which comes after the rules that use them.
+    let field_names:Vec<&str> = system.world.iter().flat_map(|w| w.settings.iter())
+        .filter(|s| s.key.text == "field")
+        .filter_map(|s| s.values.first().map(|v| v.text.as_str()))
+        .collect();
+
+    let mut rules = vec![];
+    for node in system.rules.iter() {
+        // Rules for state 0 are not allowed
+        if node.owner.value == 0 {
+            errors.push(SemanticError { msg:String::from("Rules for state 0 (Dead State) are not permitted."), span:node.owner.span });
+        }
+        else {
+            check_state(&node.owner, &mut errors);
+        }
+        check_state(&node.next, &mut errors);
+        if let Some(o) = &node.offspring {
+            check_state(o, &mut errors);
+        }
+        match &node.neighbors {
+            NeighborClause::Explicit { index, state, .. } => {
+                if index.value < 0 || index.value > 7 {
+                    errors.push(SemanticError { msg:format!("Neighbor {} does not exist, neighbors are numbered 0 to 7.", index.value), span:index.span });
+                }
+                if let Some(s) = state {
+                    check_state(s, &mut errors);
+                }
+            },
+            NeighborClause::Any { state, count, .. } => {
+                check_state(state, &mut errors);
+                if let Some(c) = count {
+                    if c.value > 8 {
+                        errors.push(SemanticError { msg:format!("A cell only has 8 neighbors, {} can never match.", c.value), span:c.span });
+                    }
+                }
+            },
+            NeighborClause::All { state, .. } => check_state(state, &mut errors)
+        };
+        let mut rule = lower_rule(node);
+        rule.layers = node.layers.iter().filter_map(|c| lower_condition(c, &system.layers, &mut errors)).collect();
+        for term in node.fields.iter() {
+            let field = match field_names.iter().position(|f| *f == term.field.text) {
+                Some(f) => f,
+                None => {
+                    errors.push(SemanticError { msg:format!("Unknown field '{}', fields are declared in the world section.", term.field.text), span:term.field.span });
+                    continue;
+                }
+            };
+            match term.op {
+                FieldOp::Above => rule.field_checks.push(FieldCheck { field, above:true, value:term.amount.value }),
+                FieldOp::Below => rule.field_checks.push(FieldCheck { field, above:false, value:term.amount.value }),
+                FieldOp::Deposit => rule.deposits.push(Deposit { field, amount:term.amount.value })
+            };
+        }
+        rules.push(rule);
+    }
+
+    let mut render_rules = RenderRules::new_blank();
+    if let Some(render) = &system.render {
+        render_rules.cell_size = render.cell_size.value as usize;
+        render_rules.grid_width = render.grid_width.value as usize;
+        render_rules.grid_height = render.grid_height.value as usize;
+        for c in render.colors.iter() {
+            check_state(&c.state, &mut errors);
+            render_rules.set_color(c.state.value, c.color);
+        }
+    }
+
+    if let Some(seed) = &system.seed {
+        for p in seed.points.iter() {
+            check_state(&p.state, &mut errors);
+            if p.x.value as usize >= render_rules.grid_width || p.y.value as usize >= render_rules.grid_height {
+                errors.push(SemanticError { msg:format!("Seed cell ({}, {}) is outside of the grid.", p.x.value, p.y.value), span:p.span });
+                continue;
+            }
+            render_rules.add_state_point(StatePoint::new(p.x.value as usize, p.y.value as usize, p.state.value));
+        }
+    }
+
+    for section in system.layers.iter() {
+        let n = section.n_states.value;
+        if n < 1 || n as usize > MAX_LAYER_STATES {
+            errors.push(SemanticError { msg:format!("A layer needs between 1 and {} states.", MAX_LAYER_STATES), span:section.n_states.span });
+        }
+        if section.names.len() > n.max(0) as usize {
+            errors.push(SemanticError { msg:format!("{} state names given for a layer with {} states.", section.names.len(), n), span:section.span });
+        }
+        let mut layer = Layer::new(section.name.text.clone(), n.max(0) as usize, render_rules.grid_width, render_rules.grid_height);
+        for c in section.colors.iter() {
+            check_layer_state(&c.state, n, &mut errors);
+            layer.set_color(c.state.value, c.color);
+        }
+        if let Some(seed) = system.layer_seeds.iter().find(|s| s.layer.as_ref().is_some_and(|l| l.text == section.name.text)) {
+            for p in seed.points.iter() {
+                check_layer_state(&p.state, n, &mut errors);
+                if p.x.value as usize >= render_rules.grid_width || p.y.value as usize >= render_rules.grid_height {
+                    errors.push(SemanticError { msg:format!("Seed cell ({}, {}) is outside of the grid.", p.x.value, p.y.value), span:p.span });
+                    continue;
+                }
+                layer.set(p.x.value as usize, p.y.value as usize, p.state.value);
+            }
+        }
+        render_rules.add_layer(layer);
+    }
+
+    let mut world = WorldConfig::new_blank();
+    if let Some(section) = &system.world {
+        for setting in section.settings.iter() {
+            lower_setting(setting, &mut world, &mut errors);
+        }
+        if world.dimensions == 1 {
+            check_one_dimensional(system, section, &world, n_states, &mut errors);
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Program { rule_set:RuleSet::new(rules, n_states as usize), render_rules, world })
+}
+
+/// Applies a single 'key value' line from the world section to the world config.
+fn lower_setting(setting:&Setting, world:&mut WorldConfig, errors:&mut Vec<SemanticError>) {
+    match setting.key.text.as_str() {
+        "update" => {
+            let mode = match setting.values.as_slice() {
+                [v] => UpdateMode::from_name(&v.text),
+                _ => None
+            };
+            match mode {
+                Some(m) => world.update = m,
+                None => errors.push(SemanticError { msg:String::from("Expected one update mode: synchronous, sequential, random or margolus."), span:setting.span })
+            };
+        },
+        "blocked" => {
+            let fallback = match setting.values.as_slice() {
+                [v] => BlockedMove::from_name(&v.text),
+                _ => None
+            };
+            match fallback {
+                Some(b) => world.blocked = b,
+                None => errors.push(SemanticError { msg:String::from("Expected one blocked move fallback: stay, skip or next."), span:setting.span })
+            };
+        },
+        "bounds" => {
+            let bounds = match setting.values.as_slice() {
+                [v] => Bounds::from_name(&v.text),
+                _ => None
+            };
+            match bounds {
+                Some(b) => world.bounds = b,
+                None => errors.push(SemanticError { msg:String::from("Expected one bounds setting: fixed or unbounded."), span:setting.span })
+            };
+        },
+        "field" => {
+            let rate = |v:&Name| v.text.parse::<f32>().ok().filter(|r| (0.0..=1.0).contains(r));
+            match setting.values.as_slice() {
+                [name, diffusion, decay] if name.text.parse::<f32>().is_err() => {
+                    if world.fields.iter().any(|f| f.name == name.text) {
+                        errors.push(SemanticError { msg:format!("Field '{}' is already declared.", name.text), span:name.span });
+                    }
+                    match (rate(diffusion), rate(decay)) {
+                        (Some(diffusion), Some(decay)) => world.fields.push(FieldConfig { name:name.text.clone(), diffusion, decay }),
+                        _ => errors.push(SemanticError { msg:String::from("Field diffusion and decay rates must be between 0 and 1."), span:setting.span })
+                    };
+                },
+                _ => errors.push(SemanticError { msg:String::from("Expected a field name, then its diffusion and decay rates, e.g. 'field pher 0.2 0.05'."), span:setting.span })
+            };
+        },
+        "dimensions" => {
+            match setting.values.as_slice() {
+                [v] if v.text == "1" || v.text == "2" => world.dimensions = if v.text == "1" { 1 } else { 2 },
+                _ => errors.push(SemanticError { msg:String::from("Expected 1 or 2 dimensions."), span:setting.span })
+            };
+        },
+        "rule" => {
+            let number = match setting.values.as_slice() {
+                [v] => v.text.parse::<u8>().ok(),
+                _ => None
+            };
+            match number {
+                Some(n) => {
+                    world.elementary = Some(n);
+                    world.dimensions = 1;
+                },
+                None => errors.push(SemanticError { msg:String::from("Expected an elementary rule number from 0 to 255."), span:setting.span })
+            };
+        },
+        key => errors.push(SemanticError { msg:format!("Unknown world setting '{}'.", key), span:setting.key.span })
+    };
+}
+
+/// Checks that a one-dimensional world only uses the row: cells have a left and a right neighbor, and nothing above or below.
+/// A world driven by an elementary rule number has two states and no rules of its own.
+fn check_one_dimensional(system:&System, section:&WorldSection, world:&WorldConfig, n_states:i32, errors:&mut Vec<SemanticError>) {
+    let setting_span = |key:&str| section.settings.iter().rev().find(|s| s.key.text == key).map_or(section.span, |s| s.span);
+    if world.bounds == Bounds::Unbounded {
+        errors.push(SemanticError { msg:String::from("A one-dimensional world cannot be unbounded."), span:setting_span("bounds") });
+    }
+    if let Some(n) = world.elementary {
+        if n_states != 2 {
+            errors.push(SemanticError { msg:format!("Rule {} needs a system with 2 states.", n), span:system.header.as_ref().map_or(setting_span("rule"), |h| h.n_states.span) });
+        }
+        if let Some(rule) = system.rules.first() {
+            errors.push(SemanticError { msg:format!("A world driven by rule {} cannot have rules of its own.", n), span:rule.span });
+        }
+        if world.update != UpdateMode::Synchronous {
+            errors.push(SemanticError { msg:String::from("Elementary rules update every cell at once, the update mode must be synchronous."), span:setting_span("update") });
+        }
+    }
+
+    for rule in system.rules.iter() {
+        match &rule.neighbors {
+            NeighborClause::Explicit { index, .. } if index.value != 0 && index.value != 4 => {
+                errors.push(SemanticError { msg:String::from("In a one-dimensional world only neighbors 0 (right) and 4 (left) exist."), span:index.span });
+            },
+            NeighborClause::Any { count:Some(c), .. } if c.value > 2 => {
+                errors.push(SemanticError { msg:format!("A cell in a one-dimensional world only has 2 neighbors, {} can never match.", c.value), span:c.span });
+            },
+            _ => ()
+        };
+        if let MoveKind::Direction(d @ ('u' | 'd')) = rule.move_to.kind {
+            errors.push(SemanticError { msg:format!("Cells in a one-dimensional world cannot move '{}', only left or right.", d), span:rule.move_to.span });
+        }
+    }
+    for p in system.seed.iter().flat_map(|s| s.points.iter()) {
+        if p.y.value != 0 {
+            errors.push(SemanticError { msg:format!("Seed cell ({}, {}) is not on the row of a one-dimensional world, y must be 0.", p.x.value, p.y.value), span:p.span });
+        }
+    }
+}
+
+/// Checks that a state written in a layer section or layer seed exists in the layer.
+fn check_layer_state(n:&Number, n_states:i32, errors:&mut Vec<SemanticError>) {
+    if n.value < 0 || n.value >= n_states {
+        errors.push(SemanticError { msg:format!("State {} is out of range for a layer with {} states.", n.value, n_states), span:n.span });
+    }
+}
+
+/// Resolves the layer and state names of a layer condition, reporting the ones that do not exist.
+fn lower_condition(condition:&LayerCondition, layers:&[LayerSection], errors:&mut Vec<SemanticError>) -> Option<LayerCheck> {
+    let layer = match layers.iter().position(|l| l.name.text == condition.layer.text) {
+        Some(i) => i,
+        None => {
+            errors.push(SemanticError { msg:format!("Unknown layer '{}'.", condition.layer.text), span:condition.layer.span });
+            return None;
+        }
+    };
+    let section = &layers[layer];
+    let state = match (section.names.iter().position(|n| n.text == condition.state.text), condition.state.text.parse::<i32>()) {
+        (Some(i), _) => i as i32,
+        (None, Ok(v)) if v >= 0 && v < section.n_states.value => v,
+        _ => {
+            errors.push(SemanticError { msg:format!("Layer '{}' has no state '{}'.", section.name.text, condition.state.text), span:condition.state.span });
+            return None;
+        }
+    };
+    let neighbors = match &condition.clause {
+        LayerClause::Here => LayerNeighbors::Here,
+        LayerClause::Explicit { index } => {
+            if index.value < 0 || index.value > 7 {
+                errors.push(SemanticError { msg:format!("Neighbor {} does not exist, neighbors are numbered 0 to 7.", index.value), span:index.span });
+                return None;
+            }
+            LayerNeighbors::One(index.value as usize)
+        },
+        LayerClause::Any { exact, count } => {
+            let n = count.as_ref().map_or(1, |c| c.value);
+            if let Some(c) = count.as_ref().filter(|c| c.value > 8) {
+                errors.push(SemanticError { msg:format!("A cell only has 8 neighbors, {} can never match.", c.value), span:c.span });
+            }
+            if *exact { LayerNeighbors::Exactly(n) } else { LayerNeighbors::AtLeast(n) }
+        },
+        LayerClause::All => LayerNeighbors::AtLeast(1)
+    };
+    Some(LayerCheck { layer, neighbors, state })
+}
+
+/// Converts a single rule node into the BioRule used by the processor.
+/// Layer conditions are left out, they are resolved against the layer sections when lowering a whole system.
+pub fn lower_rule(node:&RuleNode) -> BioRule {
+    let mut rule = BioRule::new_blank();
+    rule.owner_state = node.owner.value;
+    rule.next_state = node.next.value;
+    rule.offspring = node.offspring.as_ref().map_or(0, |o| o.value);
+
+    match &node.neighbors {
+        NeighborClause::Explicit { index, state, .. } => {
+            rule.neighbors.push(index.value);
+            rule.neighbors_state = state.as_ref().map_or(0, |s| s.value);
+        },
+        NeighborClause::Any { exact, count, state, .. } => {
+            rule.any_neighbor = true;
+            rule.any_neighbor_exact = *exact;
+            if let Some(c) = count {
+                rule.any_neighbor_count = c.value;
+            }
+            rule.neighbors_state = state.value;
+        },
+        NeighborClause::All { state, .. } => {
+            rule.neighbors.extend(0..8);
+            rule.neighbors_state = state.value;
+        }
+    };
+
+    rule.move_to = match node.move_to.kind {
+        MoveKind::Direction(d) => BioMove::new_const(d),
+        MoveKind::Random => BioMove::new_rand(),
+        MoveKind::Stay => BioMove::new_const('_'),
+        MoveKind::Absorb => BioMove::new_const('@')
+    };
+    rule
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tokenizer::Tokenizer, parser::Parser};
+    use super::lower;
+
+    fn lower_source(src:&str) -> Result<super::Program, Vec<super::SemanticError>> {
+        let mut t = Tokenizer::new(src.to_string());
+        t.start().unwrap();
+        lower(&Parser::new(t.tokens).start().unwrap())
+    }
+
+    #[test]
+    fn lowers_rules_by_state() {
+        let program = lower_source("states 3\n1 =3.1 2 _ 2\n2 *.0 _ ^ 1\n").ok().unwrap();
+        let rules = program.rule_set.state_rules(1).unwrap();
+
+        assert_eq!(rules.len(), 1);
+        assert!(rules[0].any_neighbor_exact);
+        assert_eq!(rules[0].any_neighbor_count, 3);
+        assert!(program.rule_set.state_rules(2).unwrap()[0].move_to.is_random);
+    }
+
+    #[test]
+    fn reports_every_out_of_range_state() {
+        let errors = lower_source("states 2\n0 *.0 _ _ 1\n3 *.0 _ _ 4\n").err().unwrap();
+
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[1].span.line, 3);
+        assert_eq!(errors[2].span.col, 11);
+    }
+
+    #[test]
+    fn lowers_world_settings() {
+        let program = lower_source("states 2\n1 *.0 _ _ 1\nworld\nupdate margolus\nblocked next\nbounds unbounded\n").ok().unwrap();
+        assert_eq!(program.world.update, super::UpdateMode::Margolus);
+        assert_eq!(program.world.blocked, super::BlockedMove::Next);
+        assert_eq!(program.world.bounds, super::Bounds::Unbounded);
+
+        let errors = lower_source("states 2\nworld\nupdate sideways\nwrap yes\n").err().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[1].msg.contains("Unknown world setting 'wrap'"));
+    }
+
+    #[test]
+    fn lowers_layers_and_layer_checks() {
+        let program = lower_source("states 2\n1 ^0.0 _ r 1 terrain 0.wall\nrender 1 4 3\nlayer terrain 2 soil wall\nwall 404040\nseed terrain\n3 2 wall\n").ok().unwrap();
+        let check = &program.rule_set.state_rules(1).unwrap()[0].layers[0];
+        assert_eq!((check.layer, check.neighbors, check.state), (0, super::LayerNeighbors::One(0), 1));
+
+        let mut render_rules = program.render_rules;
+        let layers = render_rules.take_layers();
+        assert_eq!((layers[0].width(), layers[0].get(3, 2), layers[0].get(0, 0)), (4, 1, 0));
+        assert_eq!(layers[0].get_color(1), Some(0x404040FF));
+
+        let errors = lower_source("states 2\n1 ^0.0 _ r 1 rock.1 terrain.lava terrain 9.0\nlayer terrain 2\nseed terrain\n0 0 2\n").err().unwrap();
+        let messages:Vec<&str> = errors.iter().map(|e| e.msg.as_str()).collect();
+        assert_eq!(messages, vec!["Unknown layer 'rock'.", "Layer 'terrain' has no state 'lava'.", "Neighbor 9 does not exist, neighbors are numbered 0 to 7.",
+            "State 2 is out of range for a layer with 2 states."]);
+    }
+
+    #[test]
+    fn lowers_fields_and_field_terms() {
+        let program = lower_source("states 2\n1 ^0.0 _ r 1 field(food)<1 field(pher)>0.5 field(pher)+2\nworld\nfield food 0 0\nfield pher 0.25 0.05\n").ok().unwrap();
+        let rule = &program.rule_set.state_rules(1).unwrap()[0];
+        assert_eq!(rule.field_checks, vec![super::FieldCheck { field:0, above:false, value:1.0 }, super::FieldCheck { field:1, above:true, value:0.5 }]);
+        assert_eq!(rule.deposits, vec![super::Deposit { field:1, amount:2.0 }]);
+        assert_eq!((program.world.fields[1].name.as_str(), program.world.fields[1].diffusion), ("pher", 0.25));
+
+        let errors = lower_source("states 2\n1 ^0.0 _ r 1 field(smell)>1\nworld\nfield pher 2 0\nfield pher\n").err().unwrap();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].msg.contains("Unknown field 'smell'"));
+        assert!(errors[1].msg.contains("between 0 and 1"));
+    }
+
+    #[test]
+    fn checks_one_dimensional_worlds() {
+        let program = lower_source("states 2\nrender 4 64 32\nseed\n32 0 1\nworld\nrule 30\n").ok().unwrap();
+        assert_eq!((program.world.dimensions, program.world.elementary), (1, Some(30)));
+
+        let errors = lower_source("states 3\n1 ^3.1 _ u 1\n1 2.1 _ _ 1\nrender 4 64 32\nseed\n3 1 1\nworld\nrule 300\ndimensions 1\n").err().unwrap();
+        let messages:Vec<&str> = errors.iter().map(|e| e.msg.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Expected an elementary rule number from 0 to 255.",
+            "A cell in a one-dimensional world only has 2 neighbors, 3 can never match.",
+            "Cells in a one-dimensional world cannot move 'u', only left or right.",
+            "In a one-dimensional world only neighbors 0 (right) and 4 (left) exist.",
+            "Seed cell (3, 1) is not on the row of a one-dimensional world, y must be 0."
+        ]);
+
+        let errors = lower_source("states 3\n1 4.1 _ _ 2\nworld\nrule 110\n").err().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[1].msg.contains("cannot have rules of its own"));
+    }
+
+    #[test]
+    fn gives_every_rule_its_own_id() {
+        let program = lower_source("states 3\n1 ^0.0 _ r 1\n1 ^0.0 _ l 1\n1 =3.1 _ _ 2\n1 ^3.1 _ _ 2\n1 4.1 _ _ 2\n1 4.2 _ _ 2\n1 4.2 _ _ 2\n2 ^0.0 _ r 1\n").ok().unwrap();
+        let mut ids:Vec<u64> = [1, 2].iter().flat_map(|s| program.rule_set.state_rules(*s).unwrap().iter().map(|r| r.id)).collect();
+        let rules = program.rule_set.state_rules(1).unwrap();
+        assert_ne!(rules[0].calc_hash(), rules[1].calc_hash());
+        assert_ne!(rules[2].calc_hash(), rules[3].calc_hash());
+        assert_eq!(rules[5].calc_hash(), rules[6].calc_hash());
+
+        // Even rules written the same way get ids of their own.
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 8);
+    }
+}
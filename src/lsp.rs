@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+
+use crate::{check::{check_source, Severity}, ast::*};
+
+// LSP constants used by the server.
+const SEVERITY_ERROR:i32 = 1;
+const SEVERITY_WARNING:i32 = 2;
+const METHOD_NOT_FOUND:i32 = -32601;
+const KIND_OPERATOR:i32 = 24;
+const KIND_KEYWORD:i32 = 14;
+const KIND_ENUM_MEMBER:i32 = 20;
+
+/// Reads one JSON-RPC message framed with a Content-Length header.
+/// Returns None once the input is closed.
+pub fn read_message<R: BufRead>(reader:&mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(v) = line.strip_prefix("Content-Length:") {
+            length = v.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header."))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes one JSON-RPC message with its Content-Length header.
+pub fn write_message<W: Write>(writer:&mut W, msg:&Value) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+/// Runs the language server until the client sends 'exit' or closes the input.
+pub fn serve<R: BufRead, W: Write>(mut reader:R, mut writer:W) -> io::Result<()> {
+    let mut server = Server::new();
+    while let Some(msg) = read_message(&mut reader)? {
+        for out in server.handle(&msg) {
+            write_message(&mut writer, &out)?;
+        }
+        if server.exited {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The language server state: the text of every document open in the editor.
+pub struct Server {
+    documents:HashMap<String, String>,
+    pub exited:bool
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server { documents:HashMap::new(), exited:false }
+    }
+
+    /// Handles one message from the client and returns the messages to send back.
+    pub fn handle(&mut self, msg:&Value) -> Vec<Value> {
+        let method = msg["method"].as_str().unwrap_or("");
+        let id = msg.get("id").cloned();
+        let params = &msg["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+
+        match method {
+            "initialize" => respond(id, json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "completionProvider": { "triggerCharacters": [" "] }
+                },
+                "serverInfo": { "name": "cellm" }
+            })),
+            "shutdown" => respond(id, Value::Null),
+            "exit" => {
+                self.exited = true;
+                vec![]
+            },
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("").to_string();
+                self.documents.insert(uri.clone(), text);
+                vec![self.publish_diagnostics(&uri)]
+            },
+            "textDocument/didChange" => {
+                // Only full document sync is supported, so the last change holds the whole text.
+                if let Some(change) = params["contentChanges"].as_array().and_then(|c| c.last()) {
+                    let text = change["text"].as_str().unwrap_or("").to_string();
+                    self.documents.insert(uri.clone(), text);
+                }
+                vec![self.publish_diagnostics(&uri)]
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                vec![json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] }
+                })]
+            },
+            "textDocument/hover" => {
+                let result = match self.hover(&uri, &params["position"]) {
+                    Some(text) => json!({ "contents": { "kind": "markdown", "value": text } }),
+                    None => Value::Null
+                };
+                respond(id, result)
+            },
+            "textDocument/definition" => {
+                let result = match self.definition(&uri, &params["position"]) {
+                    Some(span) => json!({ "uri": uri, "range": range(span) }),
+                    None => Value::Null
+                };
+                respond(id, result)
+            },
+            "textDocument/completion" => {
+                let items = self.completion(&uri, &params["position"]);
+                respond(id, json!(items))
+            },
+            _ => match id {
+                // Requests need an answer even when they are not supported. Notifications are ignored.
+                Some(id) => vec![json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": METHOD_NOT_FOUND, "message": format!("Unsupported method '{}'.", method) }
+                })],
+                None => vec![]
+            }
+        }
+    }
+
+    /// Runs the check pass on a document, reporting every error and warning found.
+    fn publish_diagnostics(&self, uri:&str) -> Value {
+        let text = self.documents.get(uri).map_or("", |t| t.as_str());
+        let diagnostics:Vec<Value> = check_source(text).1.iter().map(|d| json!({
+            "range": range(d.span),
+            "severity": match d.severity {
+                Severity::Error => SEVERITY_ERROR,
+                Severity::Warning => SEVERITY_WARNING
+            },
+            "source": "cellm",
+            "message": d.msg
+        })).collect();
+
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics }
+        })
+    }
+
+    /// Explains the part of the source under the cursor.
+    fn hover(&self, uri:&str, position:&Value) -> Option<String> {
+        let system = check_source(self.documents.get(uri)?).0?;
+        let (line, col) = from_position(position);
+
+        if let Some(h) = &system.header {
+            if h.span.contains(line, col) {
+                return Some(format!("**states {}**\n\nThe system has {} states, numbered 0 to {}. State 0 is the dead state.", h.n_states.value, h.n_states.value, h.n_states.value - 1));
+            }
+        }
+        if let Some(rule) = system.rules.iter().find(|r| r.span.contains(line, col)) {
+            return Some(hover_rule(rule, line, col));
+        }
+        if let Some(render) = &system.render {
+            if render.span.contains(line, col) {
+                return Some(format!("**render**\n\nCells are drawn {} pixels wide on a {} x {} grid.", render.cell_size.value, render.grid_width.value, render.grid_height.value));
+            }
+            if let Some(c) = render.colors.iter().find(|c| c.span.contains(line, col)) {
+                return Some(format!("**Color**\n\nState {} is drawn with RGBA color `{:08X}`.", c.state.value, c.color));
+            }
+        }
+        if let Some(seed) = &system.seed {
+            if let Some(p) = seed.points.iter().find(|p| p.span.contains(line, col)) {
+                return Some(format!("**Seed cell**\n\nCell ({}, {}) starts in state {}.", p.x.value, p.y.value, p.state.value));
+            }
+        }
+        if let Some(layer) = system.layers.iter().find(|l| l.span.contains(line, col)) {
+            return Some(format!("**layer {}**\n\nA static layer under the grid with {} states. Rules read it with conditions such as `{}.1` or `{} ^2.1`, and `seed {}` places its cells.",
+                layer.name.text, layer.n_states.value, layer.name.text, layer.name.text, layer.name.text));
+        }
+        if let Some(world) = &system.world {
+            if world.settings.iter().any(|s| s.span.contains(line, col) && s.key.text == "update") {
+                return Some(String::from("**update**\n\nHow cells are updated each step: synchronous (from the previous generation), sequential (in place, row by row), random (in place, in random order) or margolus (2x2 blocks that shift every step)."));
+            }
+            if world.settings.iter().any(|s| s.span.contains(line, col) && s.key.text == "blocked") {
+                return Some(String::from("**blocked**\n\nWhat a cell does when it should move randomly but has no empty neighbor: stay (apply the rule in place), skip (leave the cell unchanged) or next (try its next matching rule)."));
+            }
+            if world.settings.iter().any(|s| s.span.contains(line, col) && s.key.text == "bounds") {
+                return Some(String::from("**bounds**\n\nWhere the world ends: fixed (at the edges of the render grid) or unbounded (the grid grows with the pattern)."));
+            }
+            if world.settings.iter().any(|s| s.span.contains(line, col) && s.key.text == "dimensions") {
+                return Some(String::from("**dimensions**\n\n2 for a grid, or 1 for a single row where cells only have a left (4) and a right (0) neighbor. A one-dimensional world is drawn as a space-time diagram, a row per generation."));
+            }
+            if world.settings.iter().any(|s| s.span.contains(line, col) && s.key.text == "rule") {
+                return Some(String::from("**rule**\n\nAn elementary rule number from 0 to 255, e.g. 30 or 110, that drives a one-dimensional world of 2 states instead of rules."));
+            }
+            if world.settings.iter().any(|s| s.span.contains(line, col) && s.key.text == "field") {
+                return Some(String::from("**field**\n\nA field of amounts under the grid: name, diffusion rate and decay rate, both from 0 to 1. Rules read it with `field(name)>0.5` or `field(name)<0.5` and deposit into it with `field(name)+1`."));
+            }
+        }
+        None
+    }
+
+    /// Finds where the state under the cursor is defined: its name in the header,
+    /// or else its first rule, or its color if it has no rules.
+    fn definition(&self, uri:&str, position:&Value) -> Option<Span> {
+        let system = check_source(self.documents.get(uri)?).0?;
+        let (line, col) = from_position(position);
+        let state = state_at(&system, line, col)?;
+
+        // Named states are defined where they are declared.
+        if let Some(name) = system.header.as_ref().and_then(|h| h.names.get(state as usize)) {
+            return Some(name.span);
+        }
+        if let Some(rule) = system.rules.iter().find(|r| r.owner.value == state) {
+            return Some(rule.owner.span);
+        }
+        system.render.as_ref()?.colors.iter().find(|c| c.state.value == state).map(|c| c.state.span)
+    }
+
+    /// Suggests the operators, directions or keywords that fit the column the cursor is in.
+    fn completion(&self, uri:&str, position:&Value) -> Vec<Value> {
+        let text = match self.documents.get(uri) {
+            Some(t) => t,
+            None => return vec![]
+        };
+        let (line, col) = from_position(position);
+        let prefix:String = text.lines().nth(line - 1).unwrap_or("").chars().take(col - 1).collect();
+
+        // Count the columns that are already complete on this line.
+        let mut column = prefix.split_whitespace().count();
+        if !prefix.is_empty() && !prefix.ends_with(char::is_whitespace) {
+            column -= 1;
+        }
+        // State names declared in the header, read from the text so they work while the file does not parse.
+        let names:Vec<&str> = text.lines()
+            .find(|l| l.trim_start().starts_with("states"))
+            .map_or(vec![], |l| l.split('#').next().unwrap().split_whitespace().skip(2).collect());
+        let in_rule = prefix.split_whitespace().next().is_some_and(|w| w.chars().all(|c| c.is_ascii_digit()) || names.contains(&w));
+
+        let mut items:Vec<(&str, i32, &str)> = match column {
+            0 => vec![
+                ("states", KIND_KEYWORD, "Number of states in the system"),
+                ("render", KIND_KEYWORD, "Cell size, grid width and height, then a color per state"),
+                ("seed", KIND_KEYWORD, "Cells placed on the grid at the start, as x y state"),
+                ("world", KIND_KEYWORD, "Simulation settings, e.g. update margolus"),
+                ("layer", KIND_KEYWORD, "A static layer under the grid: name, number of states and state names, then a color per state")
+            ],
+            1 if in_rule => vec![
+                ("^", KIND_OPERATOR, "At least N neighbors, e.g. ^3.1"),
+                ("=", KIND_OPERATOR, "Exactly N neighbors, e.g. =3.1"),
+                ("*", KIND_OPERATOR, "Check all neighbors, e.g. *.0")
+            ],
+            2 if in_rule => vec![
+                ("_", KIND_OPERATOR, "Leave nothing behind")
+            ],
+            3 if in_rule => vec![
+                ("l", KIND_ENUM_MEMBER, "Move left"),
+                ("r", KIND_ENUM_MEMBER, "Move right"),
+                ("u", KIND_ENUM_MEMBER, "Move up"),
+                ("d", KIND_ENUM_MEMBER, "Move down"),
+                ("^", KIND_ENUM_MEMBER, "Move to a random empty neighbor"),
+                ("_", KIND_ENUM_MEMBER, "Stay in place"),
+                ("@", KIND_ENUM_MEMBER, "Absorb")
+            ],
+            _ => vec![]
+        };
+        // Any column that holds a state can use a state name.
+        if column == 0 || (in_rule && (column == 2 || column == 4)) {
+            items.extend(names.iter().map(|n| (*n, KIND_ENUM_MEMBER, "State")));
+        }
+        items.iter().map(|(label, kind, detail)| json!({ "label": label, "kind": kind, "detail": detail })).collect()
+    }
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
+}
+
+/// Describes the column of a rule under the cursor.
+fn hover_rule(rule:&RuleNode, line:usize, col:usize) -> String {
+    if rule.owner.span.contains(line, col) {
+        format!("**Owner state**\n\nThis rule applies to cells in state {}.", rule.owner.value)
+    }
+    else if rule.neighbors.span().contains(line, col) {
+        let condition = match &rule.neighbors {
+            NeighborClause::Explicit { index, state, .. } => format!("neighbor {} is in state {}", index.value, state.as_ref().map_or(0, |s| s.value)),
+            NeighborClause::Any { exact, count, state, .. } => {
+                let n = count.as_ref().map_or(1, |c| c.value);
+                match exact {
+                    true => format!("exactly {} neighbors are in state {}", n, state.value),
+                    false => format!("at least {} neighbors are in state {}", n, state.value)
+                }
+            },
+            NeighborClause::All { state, .. } => format!("one of its 8 neighbors is in state {}", state.value)
+        };
+        format!("**Neighbor clause**\n\nThe rule fires when {}.", condition)
+    }
+    else if rule.move_to.span.contains(line, col) {
+        let mov = match rule.move_to.kind {
+            MoveKind::Direction(d) => format!("The cell moves one step '{}'.", d),
+            MoveKind::Random => String::from("The cell moves to a random empty neighbor."),
+            MoveKind::Stay => String::from("The cell stays in place."),
+            MoveKind::Absorb => String::from("The cell absorbs its neighbor.")
+        };
+        format!("**Move**\n\n{}", mov)
+    }
+    else if rule.next.span.contains(line, col) {
+        format!("**Next state**\n\nThe cell becomes state {} after the rule is applied.", rule.next.value)
+    }
+    else if let Some(c) = rule.layers.iter().find(|c| c.span.contains(line, col)) {
+        let cells = match &c.clause {
+            LayerClause::Here => String::from("the cell under it is"),
+            LayerClause::Explicit { index } => format!("neighbor {} is", index.value),
+            LayerClause::Any { exact:true, count } => format!("exactly {} neighbors are", count.as_ref().map_or(1, |n| n.value)),
+            LayerClause::Any { exact:false, count } => format!("at least {} neighbors are", count.as_ref().map_or(1, |n| n.value)),
+            LayerClause::All => String::from("one of its 8 neighbors is")
+        };
+        format!("**Layer condition**\n\nThe rule only fires when {} in state {} on layer '{}'.", cells, c.state.text, c.layer.text)
+    }
+    else if let Some(t) = rule.fields.iter().find(|t| t.span.contains(line, col)) {
+        match t.op {
+            FieldOp::Above => format!("**Field condition**\n\nThe rule only fires when field '{}' is above {} at the cell.", t.field.text, t.amount.value),
+            FieldOp::Below => format!("**Field condition**\n\nThe rule only fires when field '{}' is below {} at the cell.", t.field.text, t.amount.value),
+            FieldOp::Deposit => format!("**Field deposit**\n\nAdds {} to field '{}' where the cell was, at the end of the step.", t.amount.value, t.field.text)
+        }
+    }
+    else {
+        match &rule.offspring {
+            Some(o) => format!("**Offspring**\n\nState {} is left where the cell was.", o.value),
+            None => String::from("**Offspring**\n\nNothing is left where the cell was.")
+        }
+    }
+}
+
+/// Returns the state written at the given location, if there is one.
+fn state_at(system:&System, line:usize, col:usize) -> Option<i32> {
+    let mut numbers:Vec<&Number> = vec![];
+    for rule in system.rules.iter() {
+        numbers.push(&rule.owner);
+        numbers.push(&rule.next);
+        if let Some(o) = &rule.offspring {
+            numbers.push(o);
+        }
+        match &rule.neighbors {
+            NeighborClause::Any { state, .. } | NeighborClause::All { state, .. } => numbers.push(state),
+            NeighborClause::Explicit { state, .. } => numbers.extend(state.iter())
+        };
+    }
+    if let Some(render) = &system.render {
+        numbers.extend(render.colors.iter().map(|c| &c.state));
+    }
+    if let Some(seed) = &system.seed {
+        numbers.extend(seed.points.iter().map(|p| &p.state));
+    }
+    numbers.iter().find(|n| n.span.contains(line, col)).map(|n| n.value)
+}
+
+/// Converts a span into an LSP range. LSP lines and characters start at 0.
+fn range(span:Span) -> Value {
+    json!({
+        "start": { "line": span.line - 1, "character": span.col - 1 },
+        "end": { "line": span.line - 1, "character": span.end_col - 1 }
+    })
+}
+
+/// Converts an LSP position into a line and column starting at 1.
+fn from_position(position:&Value) -> (usize, usize) {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let col = position["character"].as_u64().unwrap_or(0) as usize;
+    (line + 1, col + 1)
+}
+
+fn respond(id:Option<Value>, result:Value) -> Vec<Value> {
+    vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use serde_json::{json, Value};
+    use super::{read_message, serve, write_message};
+
+    /// Plays a whole client session against the server and returns its replies.
+    fn session(requests:Vec<Value>) -> Vec<Value> {
+        let mut input = vec![];
+        for r in requests.iter() {
+            write_message(&mut input, r).unwrap();
+        }
+        let mut output = vec![];
+        serve(Cursor::new(input), &mut output).unwrap();
+
+        let mut replies = vec![];
+        let mut reader = Cursor::new(output);
+        while let Some(msg) = read_message(&mut reader).unwrap() {
+            replies.push(msg);
+        }
+        replies
+    }
+
+    fn open(text:&str) -> Value {
+        json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": "file:///a.cell", "languageId": "cell", "version": 1, "text": text } } })
+    }
+
+    fn request(id:i32, method:&str, line:i32, character:i32) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method,
+            "params": { "textDocument": { "uri": "file:///a.cell" }, "position": { "line": line, "character": character } } })
+    }
+
+    #[test]
+    fn reports_parse_errors_as_diagnostics() {
+        let replies = session(vec![
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            open("states 2\n1 *.0 _ 1 1\n"),
+            json!({ "jsonrpc": "2.0", "method": "exit" })
+        ]);
+
+        assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+        let diagnostic = &replies[1]["params"]["diagnostics"][0];
+        assert_eq!(diagnostic["range"]["start"], json!({ "line": 1, "character": 8 }));
+    }
+
+    #[test]
+    fn hover_definition_and_completion() {
+        let replies = session(vec![
+            open("states 3\n1 =3.1 2 _ 2\n2 *.1 _ ^ 1\nrender 10 10 10\n0 000000FF\n1 FFFFFFFF\n2 FF0000FF\n"),
+            request(1, "textDocument/hover", 1, 3),
+            request(2, "textDocument/definition", 1, 11),
+            request(3, "textDocument/completion", 2, 8),
+            json!({ "jsonrpc": "2.0", "method": "exit" })
+        ]);
+
+        assert_eq!(replies[0]["params"]["diagnostics"], json!([]));
+        assert!(replies[1]["result"]["contents"]["value"].as_str().unwrap().contains("exactly 3 neighbors are in state 1"));
+        assert_eq!(replies[2]["result"]["range"]["start"], json!({ "line": 2, "character": 0 }));
+        assert_eq!(replies[3]["result"].as_array().unwrap().len(), 7);
+    }
+
+    #[test]
+    fn named_states_are_defined_in_the_header() {
+        let replies = session(vec![
+            open("states 3 dead live dying\nlive =3.live dying _ live\n"),
+            request(1, "textDocument/definition", 1, 14),
+            request(2, "textDocument/completion", 1, 0),
+            json!({ "jsonrpc": "2.0", "method": "exit" })
+        ]);
+
+        assert_eq!(replies[1]["result"]["range"]["start"], json!({ "line": 0, "character": 19 }));
+        assert!(replies[2]["result"].as_array().unwrap().iter().any(|i| i["label"] == "dying"));
+    }
+}
@@ -2,14 +2,24 @@
 extern crate colour;
 
 
+#[cfg(feature = "gui")]
 use cellm::simple_renderer::SimpleRenderer;
 use cellm::tokenizer::{Tokenizer, print_tokens};
 use cellm::parser::Parser;
-use cellm::bio::RuleSet;
+use cellm::lower::{lower, Program};
+use cellm::formatter::format_source;
+use cellm::lsp;
+use cellm::check::{check_source, Severity};
+#[cfg(feature = "gui")]
 use macroquad::prelude::*;
 use cellm::processor::Processor;
-use cellm::cli::{parse_args, print_help, Arguments};
+use cellm::grid::CellState;
+use cellm::stats::{StatsFormat, StatsWriter};
+use cellm::trace::{BreakpointObserver, TraceObserver};
+use cellm::image::write_ppm;
+use cellm::cli::{parse_args, print_help, Arguments, Command};
 
+#[cfg(feature = "gui")]
 fn window_conf() -> Conf {
     Conf {
         window_title: "Cell-Machine".to_owned(),
@@ -22,31 +32,86 @@ fn window_conf() -> Conf {
     }
 }
 
-#[macroquad::main(window_conf)]
-async fn main() {
+fn main() {
     // Begin by interpreting the command line arguments.
     let args:Vec<String> = std::env::args().collect();
-    let mut p_args = Arguments::new_blank();
 
     // If there is atleast one command line argument (besides the default working directory one)
-    if args.len() > 1 {
-        // Try and parse the arguments into p_args
-        p_args = parse_args(&args[1..].to_vec()).expect("Invalid Command Line Arguments.");
-    }
-    else {
+    if args.len() <= 1 {
         print_help();
         return;
     }
+    // Try and parse the arguments into p_args
+    let p_args = parse_args(&args[1..].to_vec()).expect("Invalid Command Line Arguments.");
+
+    match p_args.command {
+        Command::Fmt => fmt(p_args),
+        Command::Check => check(p_args),
+        Command::Run => batch(p_args),
+        Command::Lsp => lsp::serve(std::io::stdin().lock(), std::io::stdout().lock()).expect("Language server failed."),
+        #[cfg(feature = "gui")]
+        Command::Simulate => macroquad::Window::from_config(window_conf(), simulate(p_args)),
+        #[cfg(not(feature = "gui"))]
+        Command::Simulate => {
+            red_ln!("This build has no window to run simulations in. Rebuild with '--features gui', or use 'run' to run without a window.");
+            std::process::exit(1);
+        }
+    };
+}
+
+/// Rewrites the source file in the canonical layout, or only reports whether it is formatted in check mode.
+fn fmt(p_args:Arguments) {
+    let source = std::fs::read_to_string(&p_args.file_path).expect("Failed to open source file!");
+    let formatted = match format_source(source.clone()) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if p_args.check {
+        if formatted != source {
+            red_ln!("{} is not formatted.", p_args.file_path);
+            std::process::exit(1);
+        }
+    }
+    else if formatted != source {
+        std::fs::write(&p_args.file_path, formatted).expect("Failed to write source file!");
+    }
+}
+
+/// Prints every error and warning found in the source file.
+/// Exits with a non-zero status if there are errors, or warnings when they are denied.
+fn check(p_args:Arguments) {
+    let source = std::fs::read_to_string(&p_args.file_path).expect("Failed to open source file!");
+    let (_, diagnostics) = check_source(&source);
+
+    let mut failed = false;
+    for d in diagnostics.iter() {
+        match d.severity {
+            Severity::Error => { red_ln!("{}:{}", p_args.file_path, d); },
+            Severity::Warning => { yellow_ln!("{}:{}", p_args.file_path, d); }
+        };
+        failed |= d.severity == Severity::Error || p_args.deny_warnings;
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Reads the source file and lowers it into a program, exiting if it has errors.
+fn load_program(p_args:&Arguments) -> Program {
     // Read the inputted source file and tokenize it.
     let mut t = Tokenizer::new_from_file(p_args.file_path.to_string());
     let result = t.start();
 
     // Check that the result is valid.
     match result {
-        Ok(()) => { 
+        Ok(()) => {
             if p_args.verbose {
                 println!("Tokenizer Success.");
-                print_tokens(&t.tokens); 
+                print_tokens(&t.tokens);
             }
         },
         Err(e) => println!("{}", e)
@@ -54,37 +119,184 @@ async fn main() {
 
     // Parse the tokens
     let mut parser = Parser::new(t.tokens);
-    parser.start();
-    
+    let system = match parser.start() {
+        Ok(s) => s,
+        Err(e) => {
+            red_ln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     // Create the simulation ruleset from the parsed rules.
-    let rule_set = RuleSet::new(parser.rules, parser.n_states as usize);
-    
+    let program = match lower(&system) {
+        Ok(p) => p,
+        Err(errors) => {
+            for e in errors.iter() {
+                red_ln!("{}", e);
+            }
+            std::process::exit(1);
+        }
+    };
+
     if p_args.verbose {
         // Print success message
-        println!("Finished Parse!\nSystem with {} states.", parser.n_states);
-        rule_set.print();
+        println!("Finished Parse!\nSystem with {} states.", program.rule_set.nstates);
+        program.rule_set.print();
+    }
+    program
+}
+
+#[cfg(feature = "gui")]
+async fn simulate(p_args:Arguments) {
+    let program = load_program(&p_args);
+
+    // Store each cell in one byte unless there are too many states for it.
+    if program.rule_set.nstates <= u8::MAX_STATES {
+        run(Processor::<u8>::new_with_world(program.rule_set, program.render_rules, program.world), p_args).await;
+    }
+    else {
+        run(Processor::<u16>::new_with_world(program.rule_set, program.render_rules, program.world), p_args).await;
+    }
+}
+
+/// Seeds the grid from the command line options and runs the simulation in the window.
+#[cfg(feature = "gui")]
+async fn run<S:CellState>(mut processor:Processor<S>, p_args:Arguments) {
+    seed(&mut processor, &p_args);
+
+    // Load up the default renderer and run the simulation.
+    let mut sr = SimpleRenderer::new(0.1);
+    loop {
+        sr.update(&mut processor).await;
+    }
+}
+
+/// Runs the simulation without a window for the number of steps given on the command line.
+fn batch(p_args:Arguments) {
+    let program = load_program(&p_args);
+    if program.rule_set.nstates <= u8::MAX_STATES {
+        run_headless(Processor::<u8>::new_with_world(program.rule_set, program.render_rules, program.world), p_args);
     }
-    
-    // alias the width and height
-    let w = parser.render_rules.grid_width.clone();
-    let h = parser.render_rules.grid_height.clone();
+    else {
+        run_headless(Processor::<u16>::new_with_world(program.rule_set, program.render_rules, program.world), p_args);
+    }
+}
 
-    // Prepare the processor for simulation.
-    let mut processor = Processor::new(rule_set, parser.render_rules);
+/// Seeds the grid, steps it, then writes the final grid and its stats as JSON to the output file, or prints them.
+fn run_headless<S:CellState>(mut processor:Processor<S>, p_args:Arguments) {
+    seed(&mut processor, &p_args);
+    // Nothing steps back without a window.
+    processor.set_history_limit(0);
 
-    // Fill in the grid if the -fill option was used.
+    let steps = p_args.steps.unwrap_or(0);
+    // The image of a one-dimensional world has a row for every generation.
+    if p_args.image.is_some() {
+        processor.set_space_time_rows(steps as usize + 1);
+    }
+    let start = std::time::Instant::now();
+    // Rules applied are only counted by full steps, which are only worth it when the stats are written.
+    processor.count_rules = p_args.stats.is_some();
+    let mut stats = p_args.stats.as_ref().map(|path| {
+        let file = std::fs::File::create(path).expect("Failed to create stats file!");
+        StatsWriter::new(std::io::BufWriter::new(file), StatsFormat::from_path(path))
+    });
+    for _ in 0..steps {
+        let step_stats = processor.step();
+        if let Some(w) = &mut stats {
+            w.write(&step_stats).expect("Failed to write stats file!");
+        }
+        if p_args.until_settled && processor.settled().is_some() {
+            break;
+        }
+        if processor.break_reason().is_some() {
+            break;
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let snapshot = processor.snapshot();
+    let mut counts = vec![0; processor.rule_set.nstates];
+    for s in snapshot.cells.iter() {
+        counts[*s as usize] += 1;
+    }
+    let grid:Vec<&[i32]> = if snapshot.width == 0 { vec![] } else { snapshot.cells.chunks(snapshot.width).collect() };
+    let fields:serde_json::Map<String, serde_json::Value> = processor.fields().iter()
+        .map(|f| (f.name.clone(), serde_json::json!(f.values().chunks(f.width().max(1)).collect::<Vec<_>>())))
+        .collect();
+    let result = serde_json::json!({
+        "generation": snapshot.generation,
+        "rng_seed": processor.rng_seed(),
+        "width": snapshot.width,
+        "height": snapshot.height,
+        "origin": [snapshot.origin.0, snapshot.origin.1],
+        "population": counts.iter().skip(1).sum::<usize>(),
+        "settled": processor.settled().map(|s| s.to_string()),
+        "break": processor.break_reason(),
+        "counts": counts,
+        "grid": grid,
+        "fields": fields
+    });
+
+    if let Some(path) = &p_args.image {
+        let space_time = processor.space_time();
+        let rows = if space_time.is_empty() { grid.iter().map(|r| r.to_vec()).collect() } else { space_time };
+        let file = std::fs::File::create(path).expect("Failed to create image file!");
+        write_ppm(std::io::BufWriter::new(file), &rows, &processor.render_rules).expect("Failed to write image file!");
+    }
+
+    match &p_args.out {
+        Some(path) => {
+            std::fs::write(path, format!("{}\n", result)).expect("Failed to write result file!");
+            let ran = processor.generation;
+            println!("Ran {} steps in {:.3}s ({:.1} steps/sec), result written to {}.", ran, elapsed, ran as f64 / elapsed.max(1e-9), path);
+            if let Some(s) = processor.settled() {
+                println!("Settled at generation {}: {}.", ran, s);
+            }
+            if let Some(reason) = processor.break_reason() {
+                println!("Stopped at generation {}: {}.", ran, reason);
+            }
+        },
+        None => println!("{}", result)
+    };
+}
+
+/// Sets up the random number generator, the starting grid, tracing and breakpoints from the command line options.
+fn seed<S:CellState>(processor:&mut Processor<S>, p_args:&Arguments) {
+    if let Some(seed) = p_args.seed {
+        processor.reseed(seed);
+    }
+    if p_args.verbose {
+        println!("Random seed: {}", processor.rng_seed());
+    }
+
+    // Fill in the empty cells if the -fill option was used, keeping the cells from the seed section.
     if p_args.fill_state != 0 {
+        let (w, h) = processor.size();
         for y in 0..h {
             for x in 0..w {
-                processor.set_cell(p_args.fill_state, x, y);
+                if processor.get_cell(x, y) != 0 {
+                    continue;
+                }
+                if let Err(e) = processor.try_set_cell(p_args.fill_state, x, y) {
+                    red_ln!("{}", e);
+                    std::process::exit(1);
+                }
             }
         }
     }
-    processor.gen_random_seed(p_args.gen_states);
+    if p_args.gen {
+        if let Err(e) = processor.gen_random_seed(p_args.gen_states.clone()) {
+            red_ln!("{}", e);
+            std::process::exit(1);
+        }
+    }
 
-    // Load up the default renderer and run the simulation.
-    let mut sr = SimpleRenderer::new(0.1);
-    loop {
-        sr.update(&mut processor).await;
+    // Tracing and breakpoints watch the steps from here on.
+    if let Some(path) = &p_args.trace {
+        let file = std::fs::File::create(path).expect("Failed to create trace file!");
+        processor.add_observer(Box::new(TraceObserver::new(std::io::BufWriter::new(file)).expect("Failed to write trace file!")));
+    }
+    if !p_args.breakpoints.is_empty() {
+        processor.add_observer(Box::new(BreakpointObserver::new(p_args.breakpoints.clone())));
     }
 }
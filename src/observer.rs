@@ -0,0 +1,28 @@
+use crate::{bio::BioRule, processor::Point, stats::StepStats};
+
+/// Gets told what happens inside the steps of a processor, see Processor::add_observer.
+/// Every callback does nothing by default, so an observer only implements the ones it needs.
+/// Points are positions in the grid, add the processor's origin to get world positions.
+///
+/// Observers are shared with the threads that match rules when the parallel feature is on, so they
+/// must be Send and Sync. Callbacks are only made from the thread that called step.
+pub trait SimulationObserver: Send + Sync {
+    /// A step is about to start from the given generation, with the grid's top left corner at origin in the world.
+    fn on_step_start(&mut self, _generation:u64, _origin:(i64, i64)) {}
+
+    /// A cell applied a rule. Called before the cell changes.
+    fn on_rule_fired(&mut self, _point:Point, _rule:&BioRule) {}
+
+    /// A rule moved a cell to another position.
+    fn on_move(&mut self, _from:Point, _to:Point) {}
+
+    /// A cell changed state, during a step or when it was set.
+    /// During synchronous and Margolus steps this is the buffer holding the next generation.
+    fn on_cell_changed(&mut self, _point:Point, _old:i32, _new:i32) {}
+
+    /// A step finished.
+    fn on_step_end(&mut self, _stats:&StepStats) {}
+
+    /// Why the simulation should pause after the last step, if it should.
+    fn break_reason(&self) -> Option<String> { None }
+}
@@ -1,259 +1,499 @@
-use colour::red;
+use std::fmt;
 
-use crate::{tokenizer::{Token, TokenType, print_tokens}, bio::{BioRule, BioMove}, config::RenderRules};
+use crate::{tokenizer::{Token, TokenType, parse_color}, ast::*};
 /*
 Grammar
 -------
 N used to denote any number, can be nullable
 
-<sys> 	-> 'states' N<nl><rules><render><EOF>
-<rules> -> <id> <neigh> <id> <move> <id><nl><rules>
+<sys> 	-> 'states' N <names><nl><rules><sections><EOF>
+<names> -> <name><names>
+<names> -> lambda
+<rules> -> <id> <neigh> <off> <move> <id> <conds><nl><rules>
 <rules> -> lambda
-<id>	->  N
-<neigh>	-> <op>.N
+<conds> -> <name> <cond><conds>
+<conds> -> 'field' ( <name> ) <fop> <dec><conds>
+<conds> -> lambda
+<fop>	-> > | < | +
+<dec>	-> N
+<dec>	-> N.N
+<cond>	-> .<lid>
+<cond>	-> N.<lid>
+<cond>	-> <op>.<lid>
+<lid>	-> N
+<lid>	-> <name>
+<id>	-> N
+<id>	-> <name>
+<neigh>	-> N
+<neigh>	-> N.<id>
+<neigh>	-> <op>.<id>
 <op>	-> ^N
+<op>	-> =N
 <op>	-> *
+<off>	-> _
+<off>	-> <id>
+<move>	-> l | r | u | d
 <move>	-> ^
+<move>	-> _
 <move>	-> @
-<move>	-> N
-<render>-> 'render'<nl><rrule>
-<rrule> -> N <hex><nl><rrule>
+<sections> -> <render><sections>
+<sections> -> <seed><sections>
+<sections> -> <world><sections>
+<sections> -> <layer><sections>
+<sections> -> lambda
+<render>-> 'render' N N N<nl><rrule>
+<rrule> -> <id> <color><nl><rrule>
 <rrule> -> lambda
-<hex>	-> NNNNNN
+<color>	-> RRGGBB | RRGGBBAA | #RRGGBB
+<seed>	-> 'seed'<nl><srule>
+<seed>	-> 'seed' <name><nl><srule>
+<srule> -> N N <id><nl><srule>
+<srule> -> lambda
+<world> -> 'world'<nl><setting>
+<setting> -> <name> <value><nl><setting>
+<setting> -> lambda
+<layer>	-> 'layer' <name> N <names><nl><rrule>
+<value>	-> <name> <value>
+<value>	-> <dec> <value>
+<value>	-> lambda
  */
 
+/// An error found while parsing, with the location of the offending token.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ParseError {
+    pub msg:String,
+    pub span:Span
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Parsing Error on line {}: {}", self.span, self.msg)
+    }
+}
+
 /// Used to Parse cell-machine Tokens.
-/// The parser is used to create a list of BioRules
+/// The parser builds a syntax tree (see ast.rs) which can then be lowered into a RuleSet.
 pub struct Parser {
     pub input: Vec<Token>,
-    pub rules: Vec<BioRule>,
-    pub render_rules: RenderRules,
-    cur_rule:BioRule,
     cur_token:Token,
     cur_index:usize,
-    pub n_states:i32
+    system:System
 }
 
 impl Parser {
     /// Create a new parser with given token input.
     pub fn new(inp:Vec<Token>) -> Parser {
         let curt:Token = inp[0].clone();
-        Parser { input:inp, rules:vec![], cur_rule:BioRule::new_blank(), cur_index:0, cur_token:curt, n_states:0, render_rules:RenderRules::new_blank() }
+        Parser { input:inp, cur_index:0, cur_token:curt, system:System::default() }
     }
 
     /// Resets parser helper fields to initial state and begins parse.
-    pub fn start(&mut self) {
+    /// Returns the syntax tree of the whole file, or the first error found.
+    pub fn start(&mut self) -> Result<System, ParseError> {
+        self.system = System::default();
+
+        // Comments are kept in the tree, but are not part of the grammar.
+        for t in self.input.iter() {
+            if t.ttype == TokenType::Comment {
+                self.system.comments.push(Comment { text:t.lexeme.clone(), span:Span::of(t) });
+            }
+        }
+        self.input.retain(|t| !matches!(t.ttype, TokenType::Space | TokenType::Tab | TokenType::Comment));
+
         self.cur_index = 0;
         self.cur_token = self.input[0].clone();
-        self.n_states = 0;
-        //self.sanitize_whitespace();
-
-        self.input.retain(|t| match t.ttype {
-            TokenType::Space | TokenType::Tab => false,
-            _ => true
-        });
 
-        //print_tokens(&self.input);
-
-        self.sys();
-        if self.cur_token.ttype != TokenType::EOF && self.cur_token.lexeme == "render" {
-            self.advance();
-            self.parse_render_section();
-        }
+        self.sys()?;
+        Ok(self.system.clone())
     }
 
     // ---- Parsing Functions ---- //
 
-    fn sys(&mut self) {
-        // Ignore leading newline tokens
-        while self.cur_token.ttype == TokenType::Newline {
-            self.advance();
-        }
+    fn sys(&mut self) -> Result<(), ParseError> {
+        self.skip_newlines();
         // Begin looking for the 'states' keyword
         if self.cur_token.lexeme == "states" {
+            let start = Span::of(&self.cur_token);
             self.advance();
-            self.num();
-            self.consume(TokenType::Newline);
+            let n_states = self.num()?;
+            let mut header = Header { span:start.to(n_states.span), n_states, names:vec![] };
+            while self.cur_token.ttype == TokenType::Ident {
+                let name = Name { text:self.cur_token.lexeme.clone(), span:Span::of(&self.cur_token) };
+                if header.names.iter().any(|n| n.text == name.text) {
+                    return Err(self.error(format!("State name '{}' is already used.", name.text)));
+                }
+                header.span = header.span.to(name.span);
+                header.names.push(name);
+                self.advance();
+            }
+            self.system.header = Some(header);
+            self.end_line()?;
         }
         // Look through rule definitions
-        while self.cur_token.ttype == TokenType::Number || self.cur_token.ttype == TokenType::Newline {
+        while matches!(self.cur_token.ttype, TokenType::Number | TokenType::Ident | TokenType::Newline) {
             // Skip over blank lines
             if self.cur_token.ttype == TokenType::Newline {
                 self.advance();
                 continue;
             }
+            let rule = self.rule()?;
+            self.system.rules.push(rule);
+            self.end_line()?;
+        }
+
+        // Optional sections, in any order.
+        while self.cur_token.ttype == TokenType::Label {
+            match self.cur_token.lexeme.as_ref() {
+                "render" if self.system.render.is_none() => self.parse_render_section()?,
+                "seed" => self.parse_seed_section()?,
+                "world" if self.system.world.is_none() => self.parse_world_section()?,
+                "layer" => self.parse_layer_section()?,
+                _ => return Err(self.error(format!("Unexpected '{}' label.", self.cur_token.lexeme)))
+            };
+        }
 
-            // Parse the first part of the rule, the owner state.
-            self.cur_rule.owner_state = self.cur_token.lexeme.parse().expect("Invalid token lexeme for owner state.");
+        if self.cur_token.ttype != TokenType::EOF {
+            return Err(self.error(format!("Unexpected {} token.", self.cur_token.ttype)));
+        }
+        Ok(())
+    }
 
-            // Rules for state 0 are not allowed
-            if self.cur_rule.owner_state == 0 {
-                self.error("Rules for state 0 (Dead State) are not permitted.".to_string());
+    fn rule(&mut self) -> Result<RuleNode, ParseError> {
+        // Parse the first part of the rule, the owner state.
+        let owner = self.state()?;
+
+        // Parse the next chunk of the rule definition.
+        let neighbors = self.neigh()?;
+        let offspring = self.offspring()?;
+        let move_to = self.mov()?;
+
+        // Parse the 'next state' part of the rule.
+        let next = self.state()?;
+        let mut span = owner.span.to(next.span);
+
+        // Then any conditions on layers and fields.
+        let (mut layers, mut fields) = (vec![], vec![]);
+        while self.cur_token.ttype == TokenType::Ident {
+            if self.cur_token.lexeme == "field" && self.input.get(self.cur_index + 1).is_some_and(|t| t.ttype == TokenType::LParen) {
+                let term = self.field_term()?;
+                span = span.to(term.span);
+                fields.push(term);
+                continue;
             }
+            let condition = self.layer_condition()?;
+            span = span.to(condition.span);
+            layers.push(condition);
+        }
+        Ok(RuleNode { owner, neighbors, offspring, move_to, next, layers, fields, span })
+    }
 
-            // Parse the next chunk of the rule definition.
-            self.advance();
-            self.neigh();
-            self.offspring();
-            self.mov();
-
-            // Parse the 'next state' part of the rule.
-            let lex = self.consume(TokenType::Number);
-            self.cur_rule.next_state = lex.parse().expect("Invalid next state for rule!");
-            // Save the parsed rule.
-            self.rules.push(self.cur_rule.clone());
-            // Reset the rule for the next parser pass
-            self.cur_rule = BioRule::new_blank();
+    fn field_term(&mut self) -> Result<FieldTerm, ParseError> {
+        let start = Span::of(&self.cur_token);
+        self.advance();
+        self.consume(TokenType::LParen)?;
+        let field = self.word()?;
+        self.consume(TokenType::RParen)?;
+        let op = match self.cur_token.ttype {
+            TokenType::Greater => FieldOp::Above,
+            TokenType::Less => FieldOp::Below,
+            TokenType::Plus => FieldOp::Deposit,
+            _ => return Err(self.error(String::from("Expecting '>', '<' or '+' after the field.")))
+        };
+        self.advance();
+        let amount = self.decimal()?;
+        Ok(FieldTerm { span:start.to(amount.span), field, op, amount })
+    }
 
-            self.advance();
-            
+    /// Parses a number that may have a fraction, e.g. '2' or '0.25'.
+    fn decimal(&mut self) -> Result<Decimal, ParseError> {
+        let text = self.decimal_text()?;
+        match text.text.parse::<f32>() {
+            Ok(value) => Ok(Decimal { value, span:text.span }),
+            Err(_e) => Err(ParseError { msg:format!("Invalid number '{}'.", text.text), span:text.span })
         }
     }
 
-    fn neigh(&mut self) {
-        // Determine which neighbors and their state
-        if self.cur_token.ttype == TokenType::Number {
-            self.cur_rule.neighbors.push(self.cur_token.lexeme.parse().unwrap());
+    /// Reads a number that may have a fraction, kept as written.
+    fn decimal_text(&mut self) -> Result<Name, ParseError> {
+        let mut span = Span::of(&self.cur_token);
+        let mut text = self.consume(TokenType::Number)?;
+        if self.cur_token.ttype == TokenType::Dot && self.input.get(self.cur_index + 1).is_some_and(|t| t.ttype == TokenType::Number) {
             self.advance();
+            span = span.to(Span::of(&self.cur_token));
+            text = format!("{}.{}", text, self.consume(TokenType::Number)?);
         }
-        else {
-            match self.cur_token.lexeme.as_ref() {
-                "^" => self.any_neigh(false),
-                "=" => self.any_neigh(true),
-                "*" => self.all_neigh(),
-                _ => self.error(String::from("Expecting number or operator."))
-            };
-            //self.advance();
-            self.consume(TokenType::Dot);
-            let lex = self.consume(TokenType::Number);
-            self.cur_rule.neighbors_state = lex.parse().unwrap();
-        }
-        
-        
+        Ok(Name { text, span })
+    }
+
+    fn layer_condition(&mut self) -> Result<LayerCondition, ParseError> {
+        let layer = self.word()?;
+        let clause = match self.cur_token.ttype {
+            TokenType::Dot => LayerClause::Here,
+            TokenType::Number => LayerClause::Explicit { index:self.num()? },
+            TokenType::Any | TokenType::Equal => {
+                let exact = self.cur_token.ttype == TokenType::Equal;
+                self.advance();
+                let count = if self.cur_token.ttype == TokenType::Number { Some(self.num()?) } else { None };
+                LayerClause::Any { exact, count }
+            },
+            TokenType::All => {
+                self.advance();
+                LayerClause::All
+            },
+            _ => return Err(self.error(String::from("Expecting '.', a number or an operator after the layer name.")))
+        };
+        self.consume(TokenType::Dot)?;
+        let state = self.word()?;
+        Ok(LayerCondition { span:layer.span.to(state.span), layer, clause, state })
     }
 
-    fn any_neigh(&mut self, exact:bool) {
-        self.cur_rule.any_neighbor = true;
-        self.cur_rule.any_neighbor_exact = exact;
+    /// Parses a name or number, kept as written.
+    fn word(&mut self) -> Result<Name, ParseError> {
+        if !matches!(self.cur_token.ttype, TokenType::Ident | TokenType::Number) {
+            return Err(self.error(format!("Expecting a name or number, found {}.", self.cur_token.ttype)));
+        }
+        let name = Name { text:self.cur_token.lexeme.clone(), span:Span::of(&self.cur_token) };
         self.advance();
-        
+        Ok(name)
+    }
+
+    fn neigh(&mut self) -> Result<NeighborClause, ParseError> {
+        let start = Span::of(&self.cur_token);
+        // Determine which neighbors and their state
         if self.cur_token.ttype == TokenType::Number {
-            let num:i32 = self.cur_token.lexeme.parse().unwrap();
+            let index = self.num()?;
+            if self.cur_token.ttype != TokenType::Dot {
+                return Ok(NeighborClause::Explicit { span:index.span, index, state:None });
+            }
             self.advance();
-            self.cur_rule.any_neighbor_count = num;
+            let state = self.state()?;
+            return Ok(NeighborClause::Explicit { span:index.span.to(state.span), index, state:Some(state) });
         }
-    }
 
-    fn all_neigh(&mut self) {
-        self.cur_rule.neighbors.extend(0..8);
+        let clause = match self.cur_token.ttype {
+            TokenType::Any => Some(false),
+            TokenType::Equal => Some(true),
+            TokenType::All => None,
+            _ => return Err(self.error(String::from("Expecting number or operator.")))
+        };
         self.advance();
+
+        match clause {
+            Some(exact) => {
+                let mut count = None;
+                if self.cur_token.ttype == TokenType::Number {
+                    count = Some(self.num()?);
+                }
+                self.consume(TokenType::Dot)?;
+                let state = self.state()?;
+                Ok(NeighborClause::Any { exact, count, span:start.to(state.span), state })
+            },
+            None => {
+                self.consume(TokenType::Dot)?;
+                let state = self.state()?;
+                Ok(NeighborClause::All { span:start.to(state.span), state })
+            }
+        }
     }
 
-    fn offspring(&mut self) {
+    fn offspring(&mut self) -> Result<Option<Number>, ParseError> {
         // Check for offspring
         if self.cur_token.ttype == TokenType::Null {
             self.advance();
+            Ok(None)
         }
         else {
-            let lex = self.consume(TokenType::Number);
-            self.cur_rule.offspring = lex.parse().unwrap();
+            Ok(Some(self.state()?))
         }
     }
 
-    fn num(&mut self) {
-        let str_val = self.consume(TokenType::Number);
-        let i_val:i32 = str_val.parse().unwrap();
-        self.n_states = i_val;
+    fn num(&mut self) -> Result<Number, ParseError> {
+        let span = Span::of(&self.cur_token);
+        let str_val = self.consume(TokenType::Number)?;
+        match str_val.parse::<i32>() {
+            Ok(value) => Ok(Number { value, span }),
+            Err(_e) => Err(ParseError { msg:format!("Invalid number '{}'.", str_val), span })
+        }
     }
 
-    fn mov(&mut self) {
-        if self.cur_token.ttype == TokenType::Direction {
-            self.cur_rule.move_to = BioMove::new_const(self.cur_token.lexeme.chars().nth(0).unwrap());
-            self.advance();
-        }
-        else if self.cur_token.ttype == TokenType::Any {
-            self.cur_rule.move_to = BioMove::new_rand();
-            self.advance();
+    /// Parses a state, written either as a number or as a name declared in the header.
+    fn state(&mut self) -> Result<Number, ParseError> {
+        let names = self.system.header.as_ref().map_or(vec![], |h| h.names.clone());
+        self.state_in(&names)
+    }
+
+    /// Parses a state, written either as a number or as one of the given names.
+    fn state_in(&mut self, names:&[Name]) -> Result<Number, ParseError> {
+        if self.cur_token.ttype != TokenType::Ident {
+            return self.num();
         }
-        else if self.cur_token.ttype == TokenType::Null {
-            self.cur_rule.move_to = BioMove::new_const('_');
-            self.advance();
+        let span = Span::of(&self.cur_token);
+        match names.iter().position(|n| n.text == self.cur_token.lexeme) {
+            Some(i) => {
+                self.advance();
+                Ok(Number { value:i as i32, span })
+            },
+            None => Err(self.error(format!("Unknown state '{}'.", self.cur_token.lexeme)))
         }
-        else if self.cur_token.ttype == TokenType::Absorb {
-            self.cur_rule.move_to = BioMove::new_const('@');
-            self.advance();
+    }
+
+    fn mov(&mut self) -> Result<MoveNode, ParseError> {
+        let span = Span::of(&self.cur_token);
+        let kind = match self.cur_token.ttype {
+            TokenType::Direction => MoveKind::Direction(self.cur_token.lexeme.chars().next().unwrap()),
+            TokenType::Any => MoveKind::Random,
+            TokenType::Null => MoveKind::Stay,
+            TokenType::Absorb => MoveKind::Absorb,
+            _ => return Err(self.error(String::from("Invalid MOVE syntax.")))
+        };
+        self.advance();
+        Ok(MoveNode { kind, span })
+    }
+
+    fn parse_render_section(&mut self) -> Result<(), ParseError> {
+        let start = Span::of(&self.cur_token);
+        self.advance();
+
+        let cell_size = self.size()?;
+        let grid_width = self.size()?;
+        let grid_height = self.size()?;
+        let mut section = RenderSection { span:start.to(grid_height.span), cell_size, grid_width, grid_height, colors:vec![] };
+        self.end_line()?;
+
+        self.skip_newlines();
+        while matches!(self.cur_token.ttype, TokenType::Number | TokenType::Ident) {
+            // Attempt to parse the state
+            let state = self.state()?;
+
+            // The tokenizer only creates color tokens for valid colors.
+            let span = Span::of(&self.cur_token);
+            let lex = self.consume(TokenType::Color)?;
+            let color = parse_color(&lex).unwrap();
+            section.colors.push(ColorNode { span:state.span.to(span), state, color });
+            self.end_line()?;
+            self.skip_newlines();
         }
-        else {
-            self.error(String::from("Invalid MOVE syntax."));
+        self.system.render = Some(section);
+        Ok(())
+    }
+
+    fn size(&mut self) -> Result<Number, ParseError> {
+        let size = self.num();
+        match size {
+            Ok(n) if n.value >= 0 => Ok(n),
+            _ => Err(self.error("Invalid size parameter for render section!".to_string()))
         }
     }
 
-    fn parse_sizes(&mut self) {
-        for i in 0..3 {
-            let size_result = usize::from_str_radix(&self.cur_token.lexeme, 10);
-            let mut size = 0;
-            match size_result {
-                Ok(s) => size = s,
-                Err(e) => self.error("Invalid size parameter for render section!".to_string())
-            };
-            //red!("PARSING SIZE {}", size);
+    fn parse_seed_section(&mut self) -> Result<(), ParseError> {
+        let mut span = Span::of(&self.cur_token);
+        self.advance();
 
-            match i {
-                0 => self.render_rules.cell_size = size,
-                1 => self.render_rules.grid_width = size,
-                2 => self.render_rules.grid_height = size,
-                _ => ()
+        // Cells for a layer are written with the layer's own state names.
+        let mut layer = None;
+        let mut names = self.system.header.as_ref().map_or(vec![], |h| h.names.clone());
+        if self.cur_token.ttype == TokenType::Ident {
+            let name = self.word()?;
+            names = match self.system.layers.iter().find(|l| l.name.text == name.text) {
+                Some(l) => l.names.clone(),
+                None => return Err(ParseError { msg:format!("Unknown layer '{}', layers are declared before they are seeded.", name.text), span:name.span })
             };
-            self.advance();
+            if self.system.layer_seeds.iter().any(|s| s.layer.as_ref().is_some_and(|l| l.text == name.text)) {
+                return Err(ParseError { msg:format!("Layer '{}' is already seeded.", name.text), span:name.span });
+            }
+            span = span.to(name.span);
+            layer = Some(name);
+        }
+        else if self.system.seed.is_some() {
+            return Err(ParseError { msg:String::from("Unexpected 'seed' label."), span });
+        }
+        self.end_line()?;
+
+        let mut section = SeedSection { layer, points:vec![], span };
+        self.skip_newlines();
+        while self.cur_token.ttype == TokenType::Number {
+            let x = self.num()?;
+            let y = self.num()?;
+            let state = self.state_in(&names)?;
+            section.points.push(SeedPoint { span:x.span.to(state.span), x, y, state });
+            self.end_line()?;
+            self.skip_newlines();
+        }
+        if section.layer.is_some() {
+            self.system.layer_seeds.push(section);
         }
-        self.consume(TokenType::Newline);
+        else {
+            self.system.seed = Some(section);
+        }
+        Ok(())
     }
 
-    fn parse_render_section(&mut self) {
-
-        self.parse_sizes();
-
-        while self.cur_token.ttype == TokenType::Number || self.cur_token.ttype == TokenType::Newline {
+    fn parse_layer_section(&mut self) -> Result<(), ParseError> {
+        let start = Span::of(&self.cur_token);
+        self.advance();
 
-            while self.cur_token.ttype == TokenType::Newline {
-                self.advance();
-            }
-            if self.cur_token.ttype == TokenType::EOF {
-                self.advance();
-                break;
+        let name = self.word()?;
+        if self.system.layers.iter().any(|l| l.name.text == name.text) {
+            return Err(ParseError { msg:format!("Layer '{}' is already declared.", name.text), span:name.span });
+        }
+        let n_states = self.num()?;
+        let mut section = LayerSection { span:start.to(n_states.span), name, n_states, names:vec![], colors:vec![] };
+        while self.cur_token.ttype == TokenType::Ident {
+            let state_name = Name { text:self.cur_token.lexeme.clone(), span:Span::of(&self.cur_token) };
+            if section.names.iter().any(|n| n.text == state_name.text) {
+                return Err(self.error(format!("State name '{}' is already used.", state_name.text)));
             }
-
-            // Attempt to parse the state
-            let state_result = i32::from_str_radix(&self.cur_token.lexeme, 10);
-            let mut state = 0;
-            match state_result {
-                Ok(s) => state = s,
-                Err(e) => self.error("Invalid state definition in render section!".to_string())
-            };
+            section.span = section.span.to(state_name.span);
+            section.names.push(state_name);
             self.advance();
+        }
+        self.end_line()?;
+
+        self.skip_newlines();
+        while matches!(self.cur_token.ttype, TokenType::Number | TokenType::Ident) {
+            let state = self.state_in(&section.names.clone())?;
+            let span = Span::of(&self.cur_token);
+            let lex = self.consume(TokenType::Color)?;
+            let color = parse_color(&lex).unwrap();
+            section.colors.push(ColorNode { span:state.span.to(span), state, color });
+            self.end_line()?;
+            self.skip_newlines();
+        }
+        self.system.layers.push(section);
+        Ok(())
+    }
 
-            // Attempt to parse the color for the state
-            let color_result = u32::from_str_radix(&self.cur_token.lexeme, 16);
-            let mut color:u32 = 0;
+    fn parse_world_section(&mut self) -> Result<(), ParseError> {
+        let span = Span::of(&self.cur_token);
+        self.advance();
+        self.end_line()?;
 
-            match color_result {
-                Ok(c) => color = c,
-                Err(e) => self.error(format!("Invalid 32 bit color assignment for state {}", state))
-            };
+        let mut section = WorldSection { settings:vec![], span };
+        self.skip_newlines();
+        while self.cur_token.ttype == TokenType::Ident {
+            let key = Name { text:self.cur_token.lexeme.clone(), span:Span::of(&self.cur_token) };
             self.advance();
-            self.render_rules.set_color(state, color);
-            
-            // If end of file reached, advance on and end. Otherwise, expect a newline char.
-            /*if self.cur_token.ttype == TokenType::EOF {
-                self.advance();
+            let mut setting = Setting { span:key.span, key, values:vec![] };
+            while matches!(self.cur_token.ttype, TokenType::Ident | TokenType::Number | TokenType::Direction) {
+                let value = if self.cur_token.ttype == TokenType::Number {
+                    self.decimal_text()?
+                } else {
+                    let value = Name { text:self.cur_token.lexeme.clone(), span:Span::of(&self.cur_token) };
+                    self.advance();
+                    value
+                };
+                setting.span = setting.span.to(value.span);
+                setting.values.push(value);
             }
-            else {
-                self.consume(TokenType::Newline);
-            }*/
+            section.settings.push(setting);
+            self.end_line()?;
+            self.skip_newlines();
         }
+        self.system.world = Some(section);
+        Ok(())
     }
 
-    // ---- End Parsing Functions ---- //    
+    // ---- End Parsing Functions ---- //
 
     fn advance(&mut self) -> bool {
         self.cur_index += 1;
@@ -264,42 +504,132 @@ impl Parser {
         false
     }
 
-    fn consume(&mut self, tok:TokenType) -> String {
-        // Ignore Excess whitespace
-        if tok != TokenType::Space && tok != TokenType::Tab {
-            while self.cur_token.ttype == TokenType::Space || self.cur_token.ttype == TokenType::Tab {
-                self.advance();
-            }
+    fn skip_newlines(&mut self) {
+        while self.cur_token.ttype == TokenType::Newline {
+            self.advance();
+        }
+    }
+
+    /// Expects the end of a line, which may also be the end of the file.
+    fn end_line(&mut self) -> Result<(), ParseError> {
+        match self.cur_token.ttype {
+            TokenType::EOF => Ok(()),
+            _ => self.consume(TokenType::Newline).map(|_| ())
         }
+    }
+
+    fn consume(&mut self, tok:TokenType) -> Result<String, ParseError> {
         if self.cur_token.ttype == tok {
                 let lex_copy = self.cur_token.lexeme.clone();
-                //println!("Consuming {} -> {}", lex_copy, tok);
                 self.advance();
-                return lex_copy;
+                return Ok(lex_copy);
         }
-        self.error(format!("Paring Error! Expected type {}, found {}", tok, self.cur_token.ttype));
-        String::new()
+        Err(self.error(format!("Expected type {}, found {}", tok, self.cur_token.ttype)))
     }
 
-    fn error(&self, msg:String) {
-        let mut prev_token = String::from("BOF");
-        if self.cur_index > 0 {
-            prev_token = self.input[self.cur_index - 1].lexeme.clone();
-        }
-        let mut next_token = String::from("EOF");
-        if self.cur_index < self.input.len()-1 {
-            next_token = self.input[self.cur_index + 1].lexeme.clone();
-        }
+    fn error(&self, msg:String) -> ParseError {
+        ParseError { msg, span:Span::of(&self.cur_token) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tokenizer::Tokenizer, ast::*};
+    use super::Parser;
 
-        println!("... {:4} {:4} {:4} ...\n\t^ {} at token {} {}", prev_token, self.cur_token.lexeme, next_token, msg, self.cur_index, self.cur_token.lexeme);
-        std::process::exit(1);
+    fn parse(src:&str) -> System {
+        let mut t = Tokenizer::new(src.to_string());
+        t.start().unwrap();
+        Parser::new(t.tokens).start().unwrap()
     }
 
-    #[allow(dead_code)]
-    pub fn print_results(&self) {
-        println!("Parser Results:\n");
-        for r in self.rules.iter() {
-            r.print();
-        }
+    #[test]
+    fn builds_rule_nodes_with_spans() {
+        let system = parse("states 3\n1 =3.1 2 _ 2 # birth\n");
+        let rule = &system.rules[0];
+
+        assert_eq!(system.header.unwrap().n_states.value, 3);
+        assert_eq!(rule.owner.value, 1);
+        assert_eq!(rule.neighbors.span(), Span::new(2, 3, 7));
+        assert!(matches!(rule.neighbors, NeighborClause::Any { exact:true, .. }));
+        assert_eq!(rule.move_to.kind, MoveKind::Stay);
+        assert_eq!(rule.span, Span::new(2, 1, 13));
+        assert_eq!(system.comments[0].text, " birth");
+    }
+
+    #[test]
+    fn parses_render_and_seed_sections() {
+        let system = parse("states 2\n1 *.0 _ _ 0\nseed\n3 4 1\nrender 8 20 10\n0 000000FF\n1 FF00FFAA");
+        let render = system.render.unwrap();
+
+        assert_eq!(render.grid_width.value, 20);
+        assert_eq!(render.colors[1].color, 0xFF00FFAA);
+        assert_eq!(system.seed.unwrap().points[0].y.value, 4);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parses_world_settings() {
+        let system = parse("states 2\nworld\nupdate   random\n\nrender 8 20 10\n");
+        let setting = &system.world.unwrap().settings[0];
+
+        assert_eq!(setting.key.text, "update");
+        assert_eq!(setting.values[0].text, "random");
+        assert_eq!(setting.span.end_col, 16);
+        assert!(system.render.is_some());
+    }
+
+    #[test]
+    fn resolves_state_names() {
+        let system = parse("states 3 dead live dying\nlive =2.live dying _ live\nrender 8 20 10\ndying #ff8800\n");
+        let rule = &system.rules[0];
+
+        assert_eq!(system.header.unwrap().names[2].text, "dying");
+        assert_eq!(rule.owner.value, 1);
+        assert_eq!(rule.offspring.as_ref().unwrap().value, 2);
+        assert_eq!(system.render.unwrap().colors[0].color, 0xFF8800FF);
+    }
+
+    #[test]
+    fn reports_error_location() {
+        let mut t = Tokenizer::new("states 2\n1 *.0 _ 1 1\n".to_string());
+        t.start().unwrap();
+        let err = Parser::new(t.tokens).start().unwrap_err();
+
+        assert_eq!(err.span.line, 2);
+        assert_eq!(err.span.col, 9);
+    }
+
+    #[test]
+    fn parses_layers_and_their_conditions() {
+        let system = parse("states 2\n1 ^0.0 _ r 1 terrain.soil ground ^2.1\nlayer terrain 3 soil wall water\nwall 404040\nseed terrain\n2 3 water\nseed\n1 1 1\n");
+        let rule = &system.rules[0];
+
+        assert_eq!(rule.layers.len(), 2);
+        assert_eq!(rule.layers[0].clause, LayerClause::Here);
+        assert_eq!(rule.layers[1].state.text, "1");
+        assert!(matches!(rule.layers[1].clause, LayerClause::Any { exact:false, .. }));
+        assert_eq!(rule.span.end_col, 38);
+
+        let layer = &system.layers[0];
+        assert_eq!((layer.name.text.as_str(), layer.n_states.value, layer.names.len()), ("terrain", 3, 3));
+        assert_eq!(layer.colors[0].state.value, 1);
+        assert_eq!(system.layer_seeds[0].points[0].state.value, 2);
+        assert!(system.seed.is_some());
+    }
+
+    #[test]
+    fn parses_fields_and_field_terms() {
+        let system = parse("states 2\n1 ^0.0 _ r 1 field(pher)>0.5 field(pher)+2\nworld\nfield pher 0.25 0.05\n");
+        let rule = &system.rules[0];
+
+        assert_eq!(rule.fields.len(), 2);
+        assert_eq!((rule.fields[0].op, rule.fields[0].amount.value), (FieldOp::Above, 0.5));
+        assert_eq!((rule.fields[1].op, rule.fields[1].amount.value), (FieldOp::Deposit, 2.0));
+        assert_eq!(rule.fields[1].field.text, "pher");
+        assert_eq!(rule.span.end_col, 43);
+
+        let setting = &system.world.unwrap().settings[0];
+        let values:Vec<&str> = setting.values.iter().map(|v| v.text.as_str()).collect();
+        assert_eq!(values, vec!["pher", "0.25", "0.05"]);
+    }
+}
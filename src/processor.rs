@@ -1,9 +1,10 @@
-use rand::{prelude::ThreadRng, Rng};
+use std::{collections::VecDeque, io};
 
-use crate::{bio::{BioRule, RuleSet}, config::RenderRules};
-use std::collections::HashMap;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 
-#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+use crate::{bio::{FieldCheck, LayerCheck, LayerNeighbors, RuleSet, RuleTable, MAX_COUNTED_STATES}, config::{BlockedMove, Bounds, RenderRules, UpdateMode, WorldConfig}, grid::{BitSet, CellState, Grid, Snapshot}, layer::Layer, field::Field, history::{Change, History, DEFAULT_HISTORY}, detect::{cell_hash, CycleDetector, Settled, DEFAULT_MAX_PERIOD}, stats::StepStats, observer::SimulationObserver};
+
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
 pub struct Point {
     pub x:usize,
     pub y:usize
@@ -13,21 +14,6 @@ impl Point {
     pub fn new(x:usize, y:usize) -> Point {
         Point { x, y }
     }
-
-    /// Adds the value of the i32 vector to this point.
-    /// Values will not go negative, they will be clamped at zero.
-    fn add_v(&mut self, v:V) {
-        let mut nx = self.x as i32 + v.x;
-        let mut ny = self.y as i32 + v.y;
-        if nx < 0 {
-            nx = 0;
-        }
-        if ny < 0 {
-            ny = 0;
-        }
-        self.x = nx as usize;
-        self.y = ny as usize;
-    }
 }
 
 // Simple Vector
@@ -42,35 +28,470 @@ struct V {
 // 4 * 0
 // 5 6 7
 const NEIGHBOR_POS:[V; 8] = [V { x:1, y:0 }, V { x:1, y:1 }, V { x:0, y:1 }, V {x:-1, y:1}, V { x:-1, y:0 }, V { x:-1, y:-1}, V { x:0, y:-1 }, V {x:1, y:-1} ];
-//const MOVE_VALS:HashMap<char, V> = [ ('l', V {x:-1, y:0}) ].into();
 
-pub struct Processor {
+const NO_MATCH:u32 = u32::MAX;
+const CHUNK:usize = 32;                     // Unbounded grids grow and shrink by whole chunks of this many cells on a side.
+#[cfg(feature = "parallel")]
+const BAND_ROWS:usize = 16;                 // Rows matched together on one thread.
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_CELLS:usize = 1 << 14;   // Smaller steps are not worth spreading across threads.
+
+/// Runs a system of rules on a grid of cells.
+///
+/// How a step reads and writes the grid depends on the update mode in the world config:
+/// - Synchronous: every cell reads the grid as it was at the start of the step, and all changes
+///   are written to a second buffer that replaces the grid at the end of the step.
+///   When two cells write to the same position, the one later in row-major order wins.
+/// - Sequential: cells are visited in row-major order and change the grid right away, so later
+///   cells see the changes made by earlier ones.
+/// - Random order: like sequential, but the cells are visited in a new random order every step.
+/// - Margolus: the grid is split into 2x2 blocks that update like synchronous mode, except that a
+///   cell only sees neighbors inside of its block and cannot move out of it. The blocks start at
+///   (0, 0) on even generations and at (1, 1) on odd generations.
+///
+/// In every mode a cell applies at most one rule per step: the last of its rules that matches.
+/// In the in-place modes a cell that was written to during the step is not updated again.
+/// A constant move that would leave the grid (or the block) is blocked, and the cell stays put.
+/// A random move goes to one of the empty neighbors, chosen uniformly. When there is none, the
+/// blocked setting in the world config decides whether the cell stays put, skips its turn, or
+/// tries its next matching rule.
+///
+/// All randomness comes from a generator seeded with rng_seed, and cells are always visited in a
+/// fixed order, so the same rules, seed and starting grid always give the same generations.
+///
+/// Synchronous and Margolus steps run in two phases. First every cell finds its matching rule by
+/// reading the current grid. With the parallel feature this phase is split into bands of rows that
+/// run on separate threads. Then the results are merged on one thread in row-major order, which is
+/// where moves, random choices and conflicting writes are resolved. Since all randomness is in the
+/// merge, a step gives the same grid with or without the feature, for any number of threads.
+///
+/// Rule sets that only count neighbors are compiled into a lookup table, which is used to find the
+/// matching rule instead of checking each rule in turn.
+///
+/// States are stored as S, u8 by default. Use u16 for systems with more than 256 states.
+///
+/// With dirty tracking on, a synchronous step only evaluates cells near the cells that changed
+/// since the last step. A cell's next state only depends on the 5x5 window around it (its rules read
+/// its neighbors, and its neighbors can move into it), so a cell whose window did not change keeps
+/// its state. This gives the same grid as evaluating every cell. The other update modes can pass
+/// changes along further than that in one step, and random moves depend on how many times the
+/// random number generator was used, so those steps always evaluate every cell.
+///
+/// In an unbounded world the grid is a frame over world coordinates, made of whole chunks. After every
+/// step the frame is fitted to the bounding box of the active cells plus a ring of empty cells, so no cell
+/// can see past the frame or move out of it during the next step. The frame grows as soon as the pattern
+/// reaches its edge, and is cut down when it is more than four times the size the pattern needs.
+/// Only the bounding box is stored, so patterns that spread far apart still take up the whole area between them.
+/// Cells are always addressed by world position, and set_world_cell and get_world_cell reach the negative positions too.
+///
+/// The last few generations are kept as the cells each step changed, along with the state of the random number
+/// generator, so steps can be undone with step_back. Stepping forward again from an earlier generation gives the
+/// same generations as before, unless cells were set in between. Edits made with set_cell are not undone.
+///
+/// The grid is hashed every generation, and compared with the hashes of the generations before it to find out
+/// whether the simulation died out, stopped changing, or is repeating itself. Repeats are only reported when the
+/// next generation only depends on the grid: no random moves, no random order, and even periods for Margolus steps.
+///
+/// Observers added with add_observer are told about every rule applied, move made and cell changed. Dirty tracking
+/// is not used while there are observers, so they see every cell that applies a rule. For the same reason it is not
+/// used while count_rules is on, so the stats of a step count every cell that applied each rule.
+///
+/// Layers declared in the source sit under the grid, at the same world positions. Steps never change them, and
+/// moving cells pass over them. Rules can require a layer to be in a state at the cell or around it, which makes
+/// those rules skip the lookup table.
+///
+/// Fields declared in the world section hold amounts at the same world positions. Rules can require the amount at
+/// the cell to be above or below a value, and deposit amounts where the cell was. Deposits are added at the end of
+/// the step, after every field has diffused and decayed, so all cells of a step read the same amounts. Rules that
+/// read fields skip the lookup table and dirty tracking, and repeats are not reported since the grid alone does not
+/// decide the next generation.
+///
+/// A one-dimensional world is a grid of a single row, so cells only have a left and a right neighbor. The rows of the
+/// generations before the current one are kept for drawing a space-time diagram, as many as the render grid is high.
+/// Given an elementary rule number, each step applies it to every cell instead of the rules, with cells past the ends
+/// of the row read as state 0.
+pub struct Processor<S:CellState = u8> {
     pub rule_set:RuleSet,
-    grid:Vec<Vec<i32>>,
+    table:Option<RuleTable>,              // Compiled rule set, if it only has neighbor count conditions.
+    grid:Grid<S>,
+    back:Grid<S>,                         // Next generation, written to during buffered steps.
+    active:BitSet,                        // Cells of the grid that are not in state 0, aka dead state.
+    back_active:BitSet,                   // Cells of the back buffer that are not in state 0.
+    updated:BitSet,                       // Cells written to during an in-place step.
+    order:Vec<usize>,                     // Order cells are visited in during a step, reused between steps.
+    matches:Vec<u32>,                     // Rule matched by each cell in order, or NO_MATCH.
+    changed:BitSet,                       // Cells that changed since the last step started.
+    commit:BitSet,                        // Cells a dirty step may write to.
+    scan:BitSet,                          // Cells a dirty step evaluates.
+    synced:bool,                          // True if the last step was a synchronous step.
+    pub dirty_tracking:bool,              // Only evaluate cells near changes when possible.
+    pub count_rules:bool,                 // Count the cells that applied each rule in the stats of every step, which turns dirty tracking off.
+    commit_all:bool,                      // False during a dirty step, when writes are limited to commit.
     pub render_rules:RenderRules,
-    pub cell_map:HashMap<Point, i32>,     // Keeps track of where active cells are. Does not keep track of state 0, aka dead state.
-    rand:ThreadRng
+    pub world:WorldConfig,
+    layers:Vec<Layer>,                    // Static grids under the main one, in the order they were declared.
+    fields:Vec<Field>,                    // Diffusing amounts under the grid, in the order they were declared.
+    deposits:Vec<(usize, i64, i64, f32)>, // Field, world position and amount deposited so far during a step.
+    past_rows:VecDeque<Vec<i32>>,         // Rows of earlier generations of a one-dimensional world, oldest first.
+    space_time_rows:usize,                // Most rows in the space-time diagram, the current generation included.
+    pub generation:u64,
+    origin:(i64, i64),                    // World position of the top left cell of the grid, only moves in unbounded worlds.
+    history:History<S>,
+    undo:Option<Vec<(usize, S)>>,         // Old states of the cells changed so far, during a step.
+    counts:Vec<usize>,                    // Number of cells in each state.
+    moves:usize,                          // Moves made so far during a step.
+    fired:Vec<usize>,                     // Times each rule was applied so far during a step, in rule set order.
+    rule_offsets:Vec<usize>,              // Position in fired of the first rule of each state, starting at state 1.
+    rule_ids:Vec<u64>,                    // Id of each rule, in rule set order.
+    observers:Vec<Box<dyn SimulationObserver>>,
+    hash:u64,                             // Sum of the cell hashes of the grid.
+    back_hash:u64,                        // Sum of the cell hashes of the back buffer.
+    detector:CycleDetector,
+    settled:Option<Settled>,              // How the simulation settled, as of the current generation.
+    rng_seed:u64,
+    rand:StdRng
 }
 
-impl Processor {
-    pub fn new(rules:RuleSet, render_rules:RenderRules) -> Processor {
-        let ngrid = vec![vec![0; render_rules.grid_width]; render_rules.grid_height];
-        Processor { rule_set:rules, grid:ngrid, render_rules:render_rules, cell_map:HashMap::new(), rand:rand::thread_rng() }
+impl<S:CellState> Processor<S> {
+    pub fn new(rules:RuleSet, render_rules:RenderRules) -> Processor<S> {
+        Processor::new_with_world(rules, render_rules, WorldConfig::new_blank())
+    }
+
+    /// Panics if the system has more states than S can hold.
+    pub fn new_with_world(rules:RuleSet, render_rules:RenderRules, world:WorldConfig) -> Processor<S> {
+        let mut render_rules = render_rules;
+        let layers = render_rules.take_layers();
+        assert!(rules.nstates <= S::MAX_STATES, "A system with {} states does not fit in a grid of {} states.", rules.nstates, S::MAX_STATES);
+        let (w, h) = (render_rules.grid_width, if world.dimensions == 1 { 1 } else { render_rules.grid_height });
+        let space_time_rows = render_rules.grid_height;
+        let rng_seed = rand::random();
+        let nstates = rules.nstates;
+        let fields = world.fields.iter().map(|f| Field::new(f.name.clone(), f.diffusion, f.decay, w, h)).collect();
+        let mut p = Processor { table:rules.compile(), rule_set:rules, grid:Grid::new(w, h), back:Grid::new(w, h), active:BitSet::new(w * h), back_active:BitSet::new(w * h),
+            updated:BitSet::new(w * h), order:Vec::with_capacity(w * h), matches:Vec::with_capacity(w * h), changed:BitSet::new(w * h), commit:BitSet::new(w * h), scan:BitSet::new(w * h),
+            synced:false, dirty_tracking:true, count_rules:false, commit_all:true, render_rules, world, layers, fields, deposits:vec![], past_rows:VecDeque::new(), space_time_rows, generation:0, origin:(0, 0), history:History::new(DEFAULT_HISTORY), undo:None,
+            counts:vec![0; nstates], moves:0, fired:vec![], rule_offsets:vec![], rule_ids:vec![], observers:vec![],
+            hash:0, back_hash:0, detector:CycleDetector::new(DEFAULT_MAX_PERIOD), settled:None, rng_seed, rand:StdRng::seed_from_u64(rng_seed) };
+
+        p.counts[0] = w * h;
+        p.index_rules();
+
+        // Place the cells from the seed section.
+        let seed:Vec<(i32, usize, usize)> = p.render_rules.get_seed().iter().map(|sp| (sp.state, sp.x, sp.y)).collect();
+        for (state, x, y) in seed {
+            p.set_cell(state, x, y);
+        }
+        p
+    }
+
+    /// Restarts the random number generator from the given seed.
+    /// Call before gen_random_seed to make the starting grid reproducible as well.
+    pub fn reseed(&mut self, seed:u64) {
+        self.rng_seed = seed;
+        self.rand = StdRng::seed_from_u64(seed);
+    }
+
+    /// The seed the random number generator was last started from.
+    pub fn rng_seed(&self) -> u64 {
+        self.rng_seed
     }
 
+    /// Sets the cell at world position (x, y). An unbounded world grows to cover it.
+    /// Growing the grid drops the history, since older steps refer to cells by their position in the old grid.
+    /// Panics if the position is outside a bounded world or the state does not fit in S, see try_set_cell.
     pub fn set_cell(&mut self, val:i32, x:usize, y:usize) {
-        self.grid[y][x] = val;
-        self.cell_map.insert(Point::new(x, y), val);
+        if let Err(e) = self.check_cell(val, x as i64, y as i64) {
+            panic!("{}", e);
+        }
+        self.put_cell(val, x as i64, y as i64);
     }
 
-    /// Generates a random starting generation for simulation.
-    pub fn gen_random_seed(&mut self, states:Vec<i32>) {
-        let count = (self.render_rules.grid_width * self.render_rules.grid_height) / 4;
+    /// Sets the cell at world position (x, y), like set_cell.
+    /// Fails instead of panicking if the position is outside a bounded world or the state is not one of the system's states.
+    pub fn try_set_cell(&mut self, val:i32, x:usize, y:usize) -> io::Result<()> {
+        self.set_world_cell(val, x as i64, y as i64)
+    }
+
+    /// Sets the cell at world position (x, y), like try_set_cell, but anywhere in an unbounded world,
+    /// including left of and above the cells it started with.
+    pub fn set_world_cell(&mut self, val:i32, x:i64, y:i64) -> io::Result<()> {
+        if val < 0 || val as usize >= self.rule_set.nstates {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("State {} is not one of the {} states of the system.", val, self.rule_set.nstates)));
+        }
+        self.check_cell(val, x, y)?;
+        self.put_cell(val, x, y);
+        Ok(())
+    }
+
+    /// Sets a cell that passed check_cell, growing an unbounded world to cover it.
+    fn put_cell(&mut self, val:i32, x:i64, y:i64) {
+        if self.world.bounds == Bounds::Unbounded {
+            let frame = (self.origin, self.grid.width(), self.grid.height());
+            self.fit_frame((x - 1, y - 1, x + 1, y + 1), false);
+            if frame != (self.origin, self.grid.width(), self.grid.height()) {
+                self.history = History::new(self.history.limit());
+            }
+        }
+        let pos = Point::new((x - self.origin.0) as usize, (y - self.origin.1) as usize);
+        self.write_cell(val, &pos, false);
+        // Generations seen before the edit say nothing about where it goes from here.
+        self.detector.reset();
+        self.settled = None;
+    }
+
+    /// State of the cell at world position (x, y). Cells outside the grid of an unbounded world are dead.
+    /// Panics if the position is outside a bounded world, see try_get_cell.
+    pub fn get_cell(&self, x:usize, y:usize) -> i32 {
+        match self.try_get_cell(x, y) {
+            Ok(state) => state,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// State of the cell at world position (x, y), or an error if the position is outside a bounded world.
+    pub fn try_get_cell(&self, x:usize, y:usize) -> io::Result<i32> {
+        self.get_world_cell(x as i64, y as i64)
+    }
+
+    /// State of the cell at world position (x, y), like try_get_cell, but anywhere in an unbounded world.
+    /// The position stays the same cell when the grid of an unbounded world moves.
+    pub fn get_world_cell(&self, x:i64, y:i64) -> io::Result<i32> {
+        self.check_cell(0, x, y)?;
+        let (x, y) = (x - self.origin.0, y - self.origin.1);
+        if x < 0 || y < 0 || x >= self.grid.width() as i64 || y >= self.grid.height() as i64 {
+            return Ok(0);
+        }
+        Ok(self.grid.get(x as usize, y as usize).to_i32())
+    }
+
+    /// Checks that a cell can be set to the state at world position (x, y).
+    fn check_cell(&self, val:i32, x:i64, y:i64) -> io::Result<()> {
+        if val < 0 || val as usize >= S::MAX_STATES {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("State {} does not fit in a grid of {} states.", val, S::MAX_STATES)));
+        }
+        if self.world.bounds == Bounds::Fixed && (x < 0 || y < 0 || x >= self.grid.width() as i64 || y >= self.grid.height() as i64) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cell ({}, {}) is outside the {}x{} grid.", x, y, self.grid.width(), self.grid.height())));
+        }
+        Ok(())
+    }
+
+    /// Sets every cell to the dead state. Like set_cell, this is not undone by step_back.
+    pub fn clear(&mut self) {
+        let cells:Vec<usize> = self.active.iter().collect();
+        for i in cells {
+            let pos = self.point(i);
+            self.write_cell(0, &pos, false);
+        }
+        self.detector.reset();
+        self.settled = None;
+    }
+
+    /// Changes the size of a bounded world, keeping the cells that still fit.
+    /// The history and cycle detection start over, since older generations were a different size.
+    /// Fails for unbounded worlds, which size themselves.
+    pub fn resize(&mut self, width:usize, height:usize) -> io::Result<()> {
+        if self.world.bounds == Bounds::Unbounded {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unbounded worlds cannot be resized, the grid follows the cells."));
+        }
+        // One-dimensional worlds keep a single row, the height is how many generations are drawn.
+        let rows = if self.world.dimensions == 1 { 1 } else { height };
+        self.reframe((0, 0), width, rows);
+        for layer in self.layers.iter_mut() {
+            layer.resize(width, rows);
+        }
+        for field in self.fields.iter_mut() {
+            field.resize(width, rows);
+        }
+        self.past_rows.clear();
+        self.space_time_rows = height;
+        self.render_rules.grid_width = width;
+        self.render_rules.grid_height = height;
+        self.history = History::new(self.history.limit());
+        self.detector.reset();
+        self.settled = None;
+        Ok(())
+    }
+
+    /// The layers under the grid, in the order they were declared.
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// Sets the cell at world position (x, y) of a layer.
+    /// Fails if there is no such layer, the position is outside of it, or the state is not one of the layer's.
+    pub fn set_layer_cell(&mut self, layer:usize, val:i32, x:usize, y:usize) -> io::Result<()> {
+        let l = match self.layers.get_mut(layer) {
+            Some(l) => l,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("There is no layer {}, there are {} layers.", layer, self.layers.len())))
+        };
+        if !l.set(x, y, val) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot set cell ({}, {}) of layer '{}' to state {}.", x, y, l.name, val)));
+        }
+        // Cells near the change may apply different rules now.
+        self.synced = false;
+        self.detector.reset();
+        self.settled = None;
+        Ok(())
+    }
+
+    /// The fields under the grid, in the order they were declared.
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// Adds an amount to a field at world position (x, y), right away. Like set_cell, this is not undone by step_back.
+    /// Fails if there is no such field or the position is outside of it.
+    pub fn deposit(&mut self, field:usize, x:i64, y:i64, amount:f32) -> io::Result<()> {
+        let f = match self.fields.get_mut(field) {
+            Some(f) => f,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("There is no field {}, there are {} fields.", field, self.fields.len())))
+        };
+        if !f.add(x, y, amount) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot deposit at ({}, {}) of field '{}', it is outside of the field.", x, y, f.name)));
+        }
+        self.synced = false;
+        self.detector.reset();
+        self.settled = None;
+        Ok(())
+    }
+
+    /// Rows of the space-time diagram of a one-dimensional world, oldest first and ending with the current generation.
+    /// Empty for two-dimensional worlds.
+    pub fn space_time(&self) -> Vec<Vec<i32>> {
+        if self.world.dimensions != 1 {
+            return vec![];
+        }
+        let current = self.grid.cells().iter().map(|s| s.to_i32()).collect();
+        self.past_rows.iter().cloned().chain(std::iter::once(current)).collect()
+    }
+
+    /// Sets how many rows the space-time diagram keeps, the current generation included.
+    /// Starts out as the height of the render grid.
+    pub fn set_space_time_rows(&mut self, rows:usize) {
+        self.space_time_rows = rows.max(1);
+        while self.past_rows.len() >= self.space_time_rows {
+            self.past_rows.pop_front();
+        }
+    }
+
+    /// Width and height of the grid. In an unbounded world this is the current frame, see origin.
+    pub fn size(&self) -> (usize, usize) {
+        (self.grid.width(), self.grid.height())
+    }
+
+    /// Number of cells in the given state. In an unbounded world dead cells are only counted inside the grid.
+    pub fn count(&self, state:i32) -> usize {
+        if state < 0 { 0 } else { self.counts.get(state as usize).copied().unwrap_or(0) }
+    }
+
+    /// Every cell of the grid, dead ones included, as (x, y, state) in row-major order.
+    /// Positions are in the grid, add the origin to get world positions.
+    pub fn iter_cells(&self) -> impl Iterator<Item = (usize, usize, i32)> + '_ {
+        let w = self.grid.width();
+        self.grid.cells().iter().enumerate().map(move |(i, s)| (i % w, i / w, s.to_i32()))
+    }
+
+    /// World position of the top left cell of the grid. Always (0, 0) unless the world is unbounded.
+    pub fn origin(&self) -> (i64, i64) {
+        self.origin
+    }
+
+    /// The smallest world rectangle holding every active cell, as (left, top, right, bottom), or None if there are none.
+    pub fn bounding_box(&self) -> Option<(i64, i64, i64, i64)> {
+        let w = self.grid.width();
+        let (ox, oy) = self.origin;
+        // Active cells come in row-major order, so the first one is on the top row and the last on the bottom.
+        self.active.iter().fold(None, |b, i| {
+            let (x, y) = (ox + (i % w) as i64, oy + (i / w) as i64);
+            Some(match b {
+                None => (x, y, x, y),
+                Some((left, top, right, _)) => (x.min(left), top, x.max(right), y)
+            })
+        })
+    }
+
+    /// Makes the next step evaluate every cell, and recompiles the rule set.
+    /// Call after replacing the rule set, changes to it are not tracked.
+    pub fn rescan(&mut self) {
+        self.table = self.rule_set.compile();
+        self.index_rules();
+        self.synced = false;
+    }
+
+    /// Lays out the rule firing counters, one per rule in rule set order.
+    fn index_rules(&mut self) {
+        self.rule_offsets.clear();
+        self.rule_ids.clear();
+        for state in 1..self.rule_set.nstates {
+            self.rule_offsets.push(self.rule_ids.len());
+            if let Some(rules) = self.rule_set.state_rules(state) {
+                self.rule_ids.extend(rules.iter().map(|r| r.id));
+            }
+        }
+        self.fired = vec![0; self.rule_ids.len()];
+    }
+
+    /// Adds an observer that is told what happens during every step from now on.
+    pub fn add_observer(&mut self, observer:Box<dyn SimulationObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Removes every observer and hands them back, in the order they were added.
+    pub fn take_observers(&mut self) -> Vec<Box<dyn SimulationObserver>> {
+        std::mem::take(&mut self.observers)
+    }
+
+    /// Why an observer wants the simulation paused after the last step, such as a breakpoint that was hit.
+    pub fn break_reason(&self) -> Option<String> {
+        self.observers.iter().find_map(|o| o.break_reason())
+    }
+
+    /// Number of cells in each state. In an unbounded world dead cells are only counted inside the grid.
+    pub fn counts(&self) -> &[usize] {
+        &self.counts
+    }
+
+    /// Copies the current generation out of the processor.
+    /// In an unbounded world only the bounding box of the active cells is copied.
+    pub fn snapshot(&self) -> Snapshot {
+        if self.world.bounds == Bounds::Unbounded {
+            let (left, top, right, bottom) = self.bounding_box().unwrap_or((0, 0, -1, -1));
+            let (width, height) = ((right - left + 1) as usize, (bottom - top + 1) as usize);
+            let mut cells = Vec::with_capacity(width * height);
+            for y in top..=bottom {
+                for x in left..=right {
+                    cells.push(self.grid.get((x - self.origin.0) as usize, (y - self.origin.1) as usize).to_i32());
+                }
+            }
+            return Snapshot { width, height, generation:self.generation, origin:(left, top), cells };
+        }
+        Snapshot { width:self.grid.width(), height:self.grid.height(), generation:self.generation, origin:(0, 0), cells:self.grid.cells().iter().map(|s| s.to_i32()).collect() }
+    }
+
+    /// Every cell that is not in the dead state, as (x, y, state) in row-major order.
+    /// Positions are in the grid, add the origin to get world positions.
+    pub fn active_cells(&self) -> impl Iterator<Item = (usize, usize, i32)> + '_ {
+        let w = self.grid.width();
+        self.active.iter().map(move |i| (i % w, i / w, self.grid.cells()[i].to_i32()))
+    }
+
+    /// Generates a random starting generation for simulation, from the given states or else any living state.
+    /// Does nothing if no states are given and the system only has the dead state.
+    /// Fails without setting any cell if one of the states is not one of the system's states.
+    pub fn gen_random_seed(&mut self, states:Vec<i32>) -> io::Result<()> {
+        if let Some(state) = states.iter().find(|s| **s < 0 || **s as usize >= self.rule_set.nstates) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("State {} is not one of the {} states of the system.", state, self.rule_set.nstates)));
+        }
+        if states.is_empty() && self.rule_set.nstates <= 1 {
+            return Ok(());
+        }
+        let (w, h) = self.size();
+        let count = (w * h) / 4;
 
         for _i in 0..count {
             let mut state:i32 = 0;
-            let x = self.rand.gen_range(0..self.render_rules.grid_width);
-            let y = self.rand.gen_range(0..self.render_rules.grid_height);
+            let x = self.rand.gen_range(0..w);
+            let y = self.rand.gen_range(0..h);
             
             // Generate any state if none were specified for random generation.
             if states.len() == 0 {
@@ -79,139 +500,574 @@ impl Processor {
                 // Pick a random state from the specified list
                 state = states[self.rand.gen_range(0..states.len())];
             }
-            self.set_cell(state, x, y);
+            self.try_set_cell(state, x, y)?;
         }
+        Ok(())
     }
 
-    pub fn step(&mut self) {
-        let exec_rules = self.get_exec_rules();
-        //println!("Executing {} rules", exec_rules.len());
-        
+    /// Sets how many generations step_back can go back, 0 turns history off.
+    pub fn set_history_limit(&mut self, limit:usize) {
+        self.history.set_limit(limit);
+    }
+
+    /// Number of generations step_back can go back right now.
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
 
-        for rule in exec_rules.iter() {
-            //green!("\tRule {} // ", rule.1.calc_hash());
-            //e_yellow_ln!("({}, {})", rule.0.x, rule.0.y);
-            //rule.1.print();
+    /// How the simulation has settled as of the current generation, or None if it is still changing.
+    pub fn settled(&self) -> Option<Settled> {
+        self.settled
+    }
+
+    /// Sets the longest cycle that settled looks for.
+    pub fn set_max_period(&mut self, max_period:usize) {
+        self.detector.max_period = max_period;
+        self.detector.reset();
+    }
 
-            // ensure grid is in the correct state.
-            if self.grid[rule.0.y][rule.0.x] == rule.1.owner_state {
-                self.execute_rule(rule.1, rule.0.to_owned());
+    /// Hash of the whole grid. Equal grids at the same world positions have equal hashes.
+    pub fn grid_hash(&self) -> u64 {
+        self.hash
+    }
+
+    /// Undoes the last step. Returns false if there is no history left.
+    pub fn step_back(&mut self) -> bool {
+        let change = match self.history.pop() {
+            Some(c) => c,
+            None => return false
+        };
+        if let Some((origin, w, h)) = change.frame {
+            self.reframe(origin, w, h);
+        }
+        for (i, state) in change.cells {
+            let pos = self.point(i);
+            self.write_cell(state.to_i32(), &pos, false);
+        }
+        for (field, changes) in self.fields.iter_mut().zip(change.fields) {
+            field.restore(changes);
+        }
+        self.past_rows.pop_back();
+        self.generation = change.generation;
+        self.rand = change.rand;
+        self.synced = false;
+        self.detector.reset();
+        self.settled = None;
+        true
+    }
+
+    /// Goes back through the history, or steps forward, to the given generation.
+    /// Returns false if the generation is older than the history, in which case nothing changes.
+    pub fn goto(&mut self, generation:u64) -> bool {
+        if generation < self.generation && self.history.oldest().is_none_or(|g| generation < g) {
+            return false;
+        }
+        while self.generation > generation {
+            self.step_back();
+        }
+        while self.generation < generation {
+            self.step();
+        }
+        true
+    }
+
+    /// Advances the simulation by one generation, using the update mode from the world config.
+    /// Returns what happened during the step.
+    pub fn step(&mut self) -> StepStats {
+        self.detector.begin(self.hash);
+        let (rand, frame) = (self.rand.clone(), (self.origin, self.grid.width(), self.grid.height()));
+        self.undo = Some(vec![]);
+        self.moves = 0;
+        self.fired.iter_mut().for_each(|f| *f = 0);
+        for o in self.observers.iter_mut() {
+            o.on_step_start(self.generation, self.origin);
+        }
+
+        if self.world.dimensions == 1 && self.space_time_rows > 1 {
+            if self.past_rows.len() + 1 >= self.space_time_rows {
+                self.past_rows.pop_front();
             }
-            
+            self.past_rows.push_back(self.grid.cells().iter().map(|s| s.to_i32()).collect());
+        }
+
+        let synchronous = self.world.update == UpdateMode::Synchronous;
+        let dirty = synchronous && self.synced && self.dirty_tracking && !self.count_rules && !self.rule_set.has_random_moves() && !self.rule_set.has_field_checks() && self.observers.is_empty();
+
+        match (self.world.elementary, self.world.update) {
+            (Some(rule), _) => self.step_elementary(rule),
+            (None, UpdateMode::Synchronous) => self.step_buffered(false, dirty),
+            (None, UpdateMode::Margolus) => self.step_buffered(true, false),
+            (None, UpdateMode::Sequential) => self.step_in_place(false),
+            (None, UpdateMode::RandomOrder) => self.step_in_place(true)
+        };
+        self.synced = synchronous;
+        self.generation += 1;
+        for field in self.fields.iter_mut() {
+            field.diffuse();
+        }
+        for (field, x, y, amount) in std::mem::take(&mut self.deposits) {
+            self.fields[field].add(x, y, amount);
+        }
+        let cells = self.undo.take().unwrap_or_default();
+
+        // Cells are logged the first time they change, but may have changed back since.
+        let (mut births, mut deaths) = (0, 0);
+        for (i, old) in cells.iter() {
+            let new = self.grid.cells()[*i];
+            if *old == S::default() && new != S::default() {
+                births += 1;
+            }
+            else if *old != S::default() && new == S::default() {
+                deaths += 1;
+            }
+        }
+
+        if self.world.bounds == Bounds::Unbounded {
+            if let Some((left, top, right, bottom)) = self.bounding_box() {
+                self.fit_frame((left - 1, top - 1, right + 1, bottom + 1), true);
+            }
+        }
+        if self.history.limit() > 0 {
+            let moved = frame != (self.origin, self.grid.width(), self.grid.height());
+            let fields = self.fields.iter().map(|f| f.changes()).collect();
+            self.history.push(Change { generation:self.generation - 1, cells, frame:if moved { Some(frame) } else { None }, fields, rand });
         }
+
+        let deterministic = !self.rule_set.has_random_moves() && !self.rule_set.has_field_checks() && self.world.update != UpdateMode::RandomOrder;
+        let stride = if self.world.update == UpdateMode::Margolus { 2 } else { 1 };
+        self.settled = self.detector.push(self.hash, self.active.is_empty(), deterministic, stride);
+
+        let stats = StepStats { generation:self.generation, counts:self.counts.clone(), births, deaths, moves:self.moves,
+            fired:if self.count_rules { self.rule_ids.iter().copied().zip(self.fired.iter().copied()).collect() } else { vec![] } };
+        for o in self.observers.iter_mut() {
+            o.on_step_end(&stats);
+        }
+        stats
     }
 
-    /// Checks the conditions for every rule in the rule set, then returns a hashmap stating which rules meet the criteria for execution.
-    fn get_exec_rules(&mut self) -> HashMap<Point, BioRule> {
-        let mut exec_rules:HashMap<Point, BioRule> = HashMap::new();
+    /// Makes the grid of an unbounded world cover the given world rectangle, growing it by whole chunks.
+    /// With shrink set, a grid much larger than the rectangle is cut down to the chunks it touches.
+    fn fit_frame(&mut self, (left, top, right, bottom):(i64, i64, i64, i64), shrink:bool) {
+        let (ox, oy) = self.origin;
+        let (w, h) = (self.grid.width() as i64, self.grid.height() as i64);
+        let fits = left >= ox && top >= oy && right < ox + w && bottom < oy + h;
 
-        for c in self.cell_map.iter() {
-            //let c = self.cell_map.;
-            let cell = c.0;
-            // Get the state of the current cell.
-            let c_state = self.grid[cell.y][cell.x] as usize;
-            if c_state == 0 {
-                continue; // happens if second rule is trying to be applied to a cell that has already changed.
+        // Keep what the grid already covers when only growing.
+        let (left, top, right, bottom) = if shrink || w * h == 0 { (left, top, right, bottom) }
+            else { (left.min(ox), top.min(oy), right.max(ox + w - 1), bottom.max(oy + h - 1)) };
+        let c = CHUNK as i64;
+        let (cx, cy) = (left.div_euclid(c) * c, top.div_euclid(c) * c);
+        let (cw, ch) = ((right.div_euclid(c) + 1) * c - cx, (bottom.div_euclid(c) + 1) * c - cy);
+        if fits && !(shrink && w * h > 4 * cw * ch) {
+            return;
+        }
+        self.reframe((cx, cy), cw as usize, ch as usize);
+    }
+
+    /// Reallocates the grid to cover w x h cells from a new world position, keeping every active cell inside of it.
+    /// The next step evaluates every cell, since changes are tracked by position in the grid.
+    fn reframe(&mut self, origin:(i64, i64), w:usize, h:usize) {
+        let (ox, oy) = self.origin;
+        let cells:Vec<(i64, i64, S)> = self.active.iter().map(|i| {
+            let p = self.point(i);
+            (ox + p.x as i64, oy + p.y as i64, self.grid.cells()[i])
+        }).collect();
+
+        self.grid = Grid::new(w, h);
+        self.back = Grid::new(w, h);
+        for set in [&mut self.active, &mut self.back_active, &mut self.updated, &mut self.changed, &mut self.commit, &mut self.scan] {
+            *set = BitSet::new(w * h);
+        }
+        self.origin = origin;
+        self.hash = 0;
+        self.counts.iter_mut().for_each(|c| *c = 0);
+        self.counts[0] = w * h;
+        for (x, y, state) in cells {
+            if x < origin.0 || y < origin.1 || x >= origin.0 + w as i64 || y >= origin.1 + h as i64 {
+                continue;
             }
-            // Get the rules that should be applied to this cell.
-            let rules = self.rule_set.state_rules(c_state).expect(format!("Unexpected State found in system: {}", c_state).as_str());
+            self.hash = self.hash.wrapping_add(cell_hash(x, y, state.to_i32()));
+            self.counts[0] -= 1;
+            self.counts[state.to_i32() as usize] += 1;
+            let (x, y) = ((x - origin.0) as usize, (y - origin.1) as usize);
+            self.grid.set(x, y, state);
+            self.active.insert(self.grid.index(x, y));
+        }
+        self.synced = false;
+    }
 
-            //println!("Cell: {}, {} state: {}, {} rules", cell.x, cell.y, c_state, rules.len());
-            
+    /// Applies the rules of every cell against the current grid, writing the results to the back buffer.
+    /// A dirty step only evaluates the cells near changes, and only keeps writes close to changes.
+    fn step_buffered(&mut self, blocks:bool, dirty:bool) {
+        self.back.copy_from(&self.grid);
+        self.back_active.copy_from(&self.active);
+        self.back_hash = self.hash;
 
-            // Process the rules
-            for rule in rules.iter() {
-                if rule.any_neighbor == false {
-                    // Explicit Neighbor Check
-                    for n in &rule.neighbors {
-                        // Check individual neighbors for the desired state
-                        let neighbor = self.get_neighbor_state(cell.to_owned(), n.to_owned() as usize);
-                        match neighbor {
-                            Some(s) => {
-                                if s.to_owned() == rule.neighbors_state {
-                                    
-                                    exec_rules.insert(cell.to_owned(), rule.clone());
-                                }
-                            },
-                            None => ()
-                        }
-                    }
-                }
-                else if rule.any_neighbor {
-                    let mut counted_neighbors = 0;
-                    let neighbors = self.get_all_neighbors(cell.to_owned());
-                    
-                    // Count matching neighbors
-                    // Looks at every neighbor and counts how many match the desired state.
-                    for n in neighbors.iter() {
-                        match n {
-                            Some(s) => {
-                                if s.to_owned() == rule.neighbors_state {
-                                    counted_neighbors += 1;
-                                }
-                            }
-                            None => {}
-                        }
+        let dirty = dirty && self.mark_dirty();
+        let order = if dirty { self.take_dirty_order() } else { self.take_order() };
+        self.changed.clear();
+        self.commit_all = !dirty;
+
+        // Match rules against the current grid first, this part does not depend on the order of the cells.
+        let mut matches = std::mem::take(&mut self.matches);
+        matches.clear();
+        matches.resize(order.len(), NO_MATCH);
+        self.match_cells(&order, &mut matches, blocks);
+
+        // Then merge the results in row-major order, moves and random choices depend on earlier writes.
+        for (i, m) in order.iter().zip(matches.iter()) {
+            if *m == NO_MATCH {
+                continue;
+            }
+            let cell = self.point(*i);
+            let block = if blocks { Some(self.block_of(&cell)) } else { None };
+            if let Some((rule, target)) = self.find_rule(&cell, block, true, Some(*m as usize)) {
+                self.execute_rule(rule, &cell, &target, true);
+            }
+        }
+        self.order = order;
+        self.matches = matches;
+        std::mem::swap(&mut self.grid, &mut self.back);
+        std::mem::swap(&mut self.active, &mut self.back_active);
+        std::mem::swap(&mut self.hash, &mut self.back_hash);
+    }
+
+    /// Applies an elementary rule to every cell of the row, writing the results to the back buffer.
+    /// Bit 4l + 2c + r of the rule number is the next state of a cell in state c, with its left and right neighbors in states l and r.
+    fn step_elementary(&mut self, rule:u8) {
+        self.back.copy_from(&self.grid);
+        self.back_active.copy_from(&self.active);
+        self.back_hash = self.hash;
+        self.changed.clear();
+        self.commit_all = true;
+
+        let row:Vec<u8> = self.grid.cells().iter().map(|s| (*s != S::default()) as u8).collect();
+        for x in 0..row.len() {
+            let left = if x > 0 { row[x - 1] } else { 0 };
+            let right = row.get(x + 1).copied().unwrap_or(0);
+            let next = (rule >> (left * 4 + row[x] * 2 + right)) & 1;
+            self.write_cell(next as i32, &Point::new(x, 0), true);
+        }
+        std::mem::swap(&mut self.grid, &mut self.back);
+        std::mem::swap(&mut self.active, &mut self.back_active);
+        std::mem::swap(&mut self.hash, &mut self.back_hash);
+    }
+
+    /// Applies the rules of every cell directly to the grid, one cell at a time.
+    fn step_in_place(&mut self, shuffle:bool) {
+        let mut order = self.take_order();
+        if shuffle {
+            order.shuffle(&mut self.rand);
+        }
+
+        self.updated.clear();
+        self.changed.clear();
+        for i in order.iter() {
+            // Skip cells that are gone, or were moved into or changed by an earlier cell.
+            if self.updated.contains(*i) || !self.active.contains(*i) {
+                continue;
+            }
+            let cell = self.point(*i);
+            let matched = self.match_rule(&cell, None, usize::MAX);
+            if let Some((rule, target)) = self.find_rule(&cell, None, false, matched) {
+                self.execute_rule(rule, &cell, &target, false);
+                self.updated.insert(*i);
+                self.updated.insert(self.grid.index(target.x, target.y));
+            }
+        }
+        self.order = order;
+    }
+
+    /// Marks the cells whose 5x5 window changed as cells to commit, and the cells that can write to them
+    /// as cells to scan. Returns false if so much changed that scanning every cell is cheaper.
+    fn mark_dirty(&mut self) -> bool {
+        let (w, h) = (self.grid.width() as i32, self.grid.height() as i32);
+        if self.changed.count() * 49 > self.changed.capacity() {
+            return false;
+        }
+
+        self.commit.clear();
+        self.scan.clear();
+        for i in self.changed.iter() {
+            let (x, y) = ((i % w as usize) as i32, (i / w as usize) as i32);
+            for dy in -3..=3 {
+                for dx in -3..=3 {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx < 0 || ny < 0 || nx >= w || ny >= h {
+                        continue;
                     }
-                    if (rule.any_neighbor_exact && counted_neighbors == rule.any_neighbor_count) 
-                        || (!rule.any_neighbor_exact && counted_neighbors >= rule.any_neighbor_count) {                        
-                        exec_rules.insert(cell.to_owned(), rule.clone());
+                    let n = (ny * w + nx) as usize;
+                    self.scan.insert(n);
+                    if dx.abs() <= 2 && dy.abs() <= 2 {
+                        self.commit.insert(n);
                     }
                 }
             }
-            
-            
         }
-        
-        exec_rules
+        true
     }
 
-    fn get_neighbor_state(&self, cell:Point, neighbor_n:usize) -> Option<i32> {
-        // Calculate neighbor's coordinates
-        let nx = NEIGHBOR_POS[neighbor_n].x + cell.x as i32;
-        let ny = NEIGHBOR_POS[neighbor_n].y + cell.y as i32;
+    /// Takes the visit order buffer, filled with the active cells marked to scan in row-major order.
+    fn take_dirty_order(&mut self) -> Vec<usize> {
+        let mut order = std::mem::take(&mut self.order);
+        order.clear();
+        order.extend(self.scan.iter().filter(|i| self.active.contains(*i)));
+        order
+    }
 
-        // If the neighbor is off the board, return none
-        if nx < 0 || ny < 0 || nx >= self.render_rules.grid_width as i32 || ny >= self.render_rules.grid_height as i32 {
-            None
+    /// Takes the visit order buffer, filled with every active cell in row-major order.
+    fn take_order(&mut self) -> Vec<usize> {
+        let mut order = std::mem::take(&mut self.order);
+        order.clear();
+        order.extend(self.active.iter());
+        order
+    }
+
+    fn point(&self, i:usize) -> Point {
+        Point::new(i % self.grid.width(), i / self.grid.width())
+    }
+
+    /// Top left corner of the Margolus block that holds the cell in the current generation.
+    fn block_of(&self, cell:&Point) -> (i32, i32) {
+        let o = (self.generation % 2) as i32;
+        (((cell.x as i32 + o) & !1) - o, ((cell.y as i32 + o) & !1) - o)
+    }
+
+    /// Returns the index of the last of the cell's rules before 'below' whose neighbor condition holds.
+    /// Neighbors outside of the block, when one is given, are treated like neighbors off the grid.
+    fn match_rule(&self, cell:&Point, block:Option<(i32, i32)>, below:usize) -> Option<usize> {
+        let c_state = self.grid.get(cell.x, cell.y).to_i32() as usize;
+        let rules = self.rule_set.state_rules(c_state).unwrap_or_else(|| panic!("Unexpected State found in system: {}", c_state));
+
+        let neighbors = self.get_all_neighbors(cell, block);
+
+        // Compiled rule sets look up the rule from the neighbor counts.
+        if let (Some(table), usize::MAX) = (&self.table, below) {
+            let mut counts = [0; MAX_COUNTED_STATES];
+            let counted = table.counted(c_state);
+            for (k, state) in counted.iter().enumerate() {
+                counts[k] = neighbors.iter().filter(|n| **n == Some(*state)).count();
+            }
+            return table.lookup(c_state, &counts[..counted.len()]);
         }
-        // If it's a valid neighbor, return something
-        else {
-            Some(self.grid[ny as usize][nx as usize])
+
+        (0..below.min(rules.len())).rev().find(|i| {
+            let rule = &rules[*i];
+            let holds = if rule.any_neighbor {
+                // Count how many neighbors are in the desired state.
+                let counted = neighbors.iter().filter(|n| **n == Some(rule.neighbors_state)).count() as i32;
+                if rule.any_neighbor_exact { counted == rule.any_neighbor_count } else { counted >= rule.any_neighbor_count }
+            }
+            else {
+                // Explicit neighbors match if any of them is in the desired state.
+                rule.neighbors.iter().any(|n| neighbors[*n as usize] == Some(rule.neighbors_state))
+            };
+            holds && rule.layers.iter().all(|c| self.layer_check_holds(c, cell, block))
+                && rule.field_checks.iter().all(|c| self.field_check_holds(c, cell))
+        })
+    }
+
+    /// Returns true if the amount of the field at the cell is on the side of the value the check asks for.
+    fn field_check_holds(&self, check:&FieldCheck, cell:&Point) -> bool {
+        let amount = self.fields[check.field].get(self.origin.0 + cell.x as i64, self.origin.1 + cell.y as i64);
+        if check.above { amount > check.value } else { amount < check.value }
+    }
+
+    /// Returns true if the layer cells read by the check are in its state.
+    /// Neighbors off the grid or outside of the block are not read, like in the main grid.
+    fn layer_check_holds(&self, check:&LayerCheck, cell:&Point, block:Option<(i32, i32)>) -> bool {
+        let layer = &self.layers[check.layer];
+        let in_state = |p:&Point| layer.get(self.origin.0 + p.x as i64, self.origin.1 + p.y as i64) == check.state;
+        let count = || NEIGHBOR_POS.iter().filter_map(|v| self.offset(cell, v, block)).filter(|p| in_state(p)).count() as i32;
+        match check.neighbors {
+            LayerNeighbors::Here => in_state(cell),
+            LayerNeighbors::One(n) => self.offset(cell, &NEIGHBOR_POS[n], block).is_some_and(|p| in_state(&p)),
+            LayerNeighbors::AtLeast(n) => count() >= n,
+            LayerNeighbors::Exactly(n) => count() == n
         }
     }
 
-    fn get_all_neighbors(&self, cell:Point) -> Vec<Option<i32>> {
-        let mut n = vec![];
-        for i in 0..8 {
-            n.push(self.get_neighbor_state(cell.clone(), i));
+    /// Finds the matching rule of every cell in order, reading only the current grid.
+    /// With the parallel feature, large grids are split into bands of rows that are matched on separate threads.
+    fn match_cells(&self, order:&[usize], matches:&mut [u32], blocks:bool) {
+        #[cfg(feature = "parallel")]
+        if order.len() >= PARALLEL_MIN_CELLS {
+            use rayon::prelude::*;
+
+            let band = self.grid.width() * BAND_ROWS;
+            let mut bands = vec![];
+            let (mut cells, mut out) = (order, matches);
+            while !cells.is_empty() {
+                let n = cells.partition_point(|i| i / band == cells[0] / band);
+                let (c, rest) = cells.split_at(n);
+                let (o, rest_out) = std::mem::take(&mut out).split_at_mut(n);
+                bands.push((c, o));
+                cells = rest;
+                out = rest_out;
+            }
+            bands.into_par_iter().for_each(|(c, o)| self.match_band(c, o, blocks));
+            return;
+        }
+        self.match_band(order, matches, blocks);
+    }
+
+    fn match_band(&self, cells:&[usize], matches:&mut [u32], blocks:bool) {
+        for (i, m) in cells.iter().zip(matches.iter_mut()) {
+            let cell = self.point(*i);
+            let block = if blocks { Some(self.block_of(&cell)) } else { None };
+            *m = self.match_rule(&cell, block, usize::MAX).map_or(NO_MATCH, |r| r as u32);
+        }
+    }
+
+    /// Picks the rule to apply to a cell and where the cell moves to, or None if no rule applies.
+    /// Starts from the matched rule and returns it as (state, index) into the rule set.
+    /// If it is a random move with nowhere to go, the blocked setting from the world config decides what happens.
+    fn find_rule(&mut self, cell:&Point, block:Option<(i32, i32)>, buffered:bool, matched:Option<usize>) -> Option<((usize, usize), Point)> {
+        let c_state = self.grid.get(cell.x, cell.y).to_i32() as usize;
+
+        let mut matched = matched;
+        while let Some(i) = matched {
+            let rule = &self.rule_set.state_rules(c_state).unwrap()[i];
+            let (is_random, constant) = (rule.move_to.is_random, rule.move_to.constant);
+            match self.move_target(is_random, constant, cell, block, buffered) {
+                Some(target) => return Some(((c_state, i), target)),
+                None => match self.world.blocked {
+                    BlockedMove::Stay => return Some(((c_state, i), *cell)),
+                    BlockedMove::Skip => return None,
+                    BlockedMove::Next => matched = self.match_rule(cell, block, i)
+                }
+            };
+        }
+        None
+    }
+
+    fn get_neighbor_state(&self, cell:&Point, neighbor_n:usize, block:Option<(i32, i32)>) -> Option<i32> {
+        self.offset(cell, &NEIGHBOR_POS[neighbor_n], block).map(|p| self.grid.get(p.x, p.y).to_i32())
+    }
+
+    fn get_all_neighbors(&self, cell:&Point, block:Option<(i32, i32)>) -> [Option<i32>; 8] {
+        let (w, h) = (self.grid.width(), self.grid.height());
+        let mut n = [None; 8];
+
+        // Cells away from the edges can read their neighbors straight from the buffer.
+        if block.is_none() && cell.x > 0 && cell.y > 0 && cell.x + 1 < w && cell.y + 1 < h {
+            let i = self.grid.index(cell.x, cell.y) as isize;
+            for (s, v) in n.iter_mut().zip(NEIGHBOR_POS.iter()) {
+                *s = Some(self.grid.cells()[(i + v.y as isize * w as isize + v.x as isize) as usize].to_i32());
+            }
+            return n;
+        }
+        for (i, s) in n.iter_mut().enumerate() {
+            *s = self.get_neighbor_state(cell, i, block);
         }
         n
     }
 
-    fn execute_rule(&mut self, rule:&BioRule, pos:Point) {
-        let offspring = rule.offspring;
-        let bmove = rule.move_to.clone();
-        
-        // Leave the offspring
-        self.set_cell(offspring, pos.x, pos.y);
+    /// Moves a point by a vector. Returns None if the new point is off the grid or outside of the block.
+    fn offset(&self, pos:&Point, v:&V, block:Option<(i32, i32)>) -> Option<Point> {
+        let nx = pos.x as i32 + v.x;
+        let ny = pos.y as i32 + v.y;
 
-        if !bmove.is_random {
-            // Get the constant move
-            let translate = Processor::parse_dir(bmove.constant);
-            let mut next_pos = pos.clone();
-            next_pos.add_v(translate);
-            // Move and change to the next state.
-            self.set_cell(rule.next_state, next_pos.x, next_pos.y);
+        if nx < 0 || ny < 0 || nx >= self.grid.width() as i32 || ny >= self.grid.height() as i32 {
+            return None;
+        }
+        if let Some((bx, by)) = block {
+            if nx < bx || ny < by || nx > bx + 1 || ny > by + 1 {
+                return None;
+            }
+        }
+        Some(Point::new(nx as usize, ny as usize))
+    }
+
+    /// Writes a cell to the back buffer during buffered steps, or straight to the grid otherwise.
+    fn write_cell(&mut self, val:i32, pos:&Point, buffered:bool) {
+        let i = self.grid.index(pos.x, pos.y);
+        let val_s = S::from_i32(val);
+        if buffered && !self.commit_all && !self.commit.contains(i) {
+            return;
+        }
+        if val_s != self.grid.get(pos.x, pos.y) {
+            // The grid still holds the state from before the step the first time a cell changes.
+            if let (Some(undo), false) = (&mut self.undo, self.changed.contains(i)) {
+                undo.push((i, self.grid.get(pos.x, pos.y)));
+            }
+            self.changed.insert(i);
+        }
+
+        let (grid, active, hash) = if buffered { (&mut self.back, &mut self.back_active, &mut self.back_hash) } else { (&mut self.grid, &mut self.active, &mut self.hash) };
+        let (x, y) = (self.origin.0 + pos.x as i64, self.origin.1 + pos.y as i64);
+        let old = grid.get(pos.x, pos.y).to_i32();
+        if old != val {
+            for o in self.observers.iter_mut() {
+                o.on_cell_changed(*pos, old, val);
+            }
+        }
+        *hash = hash.wrapping_sub(cell_hash(x, y, old)).wrapping_add(cell_hash(x, y, val));
+        // Cells can be set to states the rule set does not know about.
+        if val as usize >= self.counts.len() {
+            self.counts.resize(val as usize + 1, 0);
+        }
+        self.counts[old as usize] -= 1;
+        self.counts[val as usize] += 1;
+        grid.set(pos.x, pos.y, val_s);
+        if val == 0 {
+            active.remove(i);
         }
         else {
-            let mut next_pos = pos.clone();
-            while self.grid[next_pos.y][next_pos.x] != 0 {
-                next_pos = pos.clone();
-                let translate = V { x:self.rand.gen_range(-1..2) as i32, y:self.rand.gen_range(-1..2) as i32 };            
-                next_pos.add_v(translate);
+            active.insert(i);
+        }
+    }
+
+    /// Works out where a rule moves the cell to.
+    /// A constant move that leaves the grid or block stays in place. A random move picks uniformly
+    /// among the neighbors that are empty in the buffer being written to, and returns None if there are none.
+    fn move_target(&mut self, is_random:bool, constant:char, pos:&Point, block:Option<(i32, i32)>, buffered:bool) -> Option<Point> {
+        if !is_random {
+            let translate = Processor::<S>::parse_dir(constant);
+            return Some(self.offset(pos, &translate, block).unwrap_or(*pos));
+        }
+
+        let target = if buffered { &self.back } else { &self.grid };
+        let mut empty = [*pos; 8];
+        let mut n_empty = 0;
+        for v in NEIGHBOR_POS.iter() {
+            if let Some(p) = self.offset(pos, v, block) {
+                if target.get(p.x, p.y) == S::default() {
+                    empty[n_empty] = p;
+                    n_empty += 1;
+                }
+            }
+        }
+        if n_empty == 0 {
+            return None;
+        }
+        Some(empty[self.rand.gen_range(0..n_empty)])
+    }
+
+    /// Leaves the offspring behind and moves the cell in its next state to the target.
+    fn execute_rule(&mut self, rule:(usize, usize), pos:&Point, target:&Point, buffered:bool) {
+        let (offspring, next_state) = {
+            let r = &self.rule_set.state_rules(rule.0).unwrap()[rule.1];
+            for o in self.observers.iter_mut() {
+                o.on_rule_fired(*pos, r);
+            }
+            for d in r.deposits.iter() {
+                self.deposits.push((d.field, self.origin.0 + pos.x as i64, self.origin.1 + pos.y as i64, d.amount));
+            }
+            (r.offspring, r.next_state)
+        };
+        self.write_cell(offspring, pos, buffered);
+        self.write_cell(next_state, target, buffered);
+        // A dirty step drops the writes of cells away from changes, so they did not apply their rule.
+        if buffered && !self.commit_all && !self.commit.contains(self.grid.index(pos.x, pos.y)) {
+            return;
+        }
+        self.fired[self.rule_offsets[rule.0 - 1] + rule.1] += 1;
+        if target != pos {
+            self.moves += 1;
+            for o in self.observers.iter_mut() {
+                o.on_move(*pos, *target);
             }
-            self.set_cell(rule.next_state, next_pos.x, next_pos.y);
         }
     }
 
@@ -227,10 +1083,26 @@ impl Processor {
 
 }
 
-//#[cfg(tests)]
+#[cfg(test)]
 mod tests {
-    use crate::{processor::Point, bio::RuleSet, config::RenderRules};
-    use super::Processor;
+    use crate::{processor::Point, bio::RuleSet, config::{RenderRules, UpdateMode}, tokenizer::Tokenizer, parser::Parser, lower::lower};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use super::{cell_hash, BlockedMove, Bounds, Processor, Settled};
+
+    const CONWAY:&str = "states 3\n1 =1.1 2 _ 2\n1 =0.1 2 _ 2\n1 =2.1 1 _ 1\n1 =3.1 1 _ 1\n1 ^4.1 2 _ 2\n2 =3.1 1 _ 1\n";
+
+    fn processor_from(src:&str, mode:UpdateMode) -> Processor {
+        let mut t = Tokenizer::new(src.to_string());
+        t.start().unwrap();
+        let program = lower(&Parser::new(t.tokens).start().unwrap()).ok().unwrap();
+        let mut p = Processor::new_with_world(program.rule_set, program.render_rules, program.world);
+        p.world.update = mode;
+        p
+    }
+
+    fn row(p:&Processor, y:usize) -> Vec<i32> {
+        (0..p.grid.width()).map(|x| p.grid.get(x, y) as i32).collect()
+    }
 
     fn blank_processor() -> Processor {
         Processor::new(
@@ -247,16 +1119,16 @@ mod tests {
         processor.set_cell(3, 4, 6); // Upper Left
         let point = Point::new(5, 5);
 
-        assert_eq!(processor.get_neighbor_state(point.clone(), 2).unwrap(), 2); // Top Neighbor
-        assert_eq!(processor.get_neighbor_state(point.clone(), 3).unwrap(), 3); // Upper Left
-        assert_eq!(processor.get_neighbor_state(point.clone(), 7).unwrap(), 0); // Bottom Right
+        assert_eq!(processor.get_neighbor_state(&point, 2, None).unwrap(), 2); // Top Neighbor
+        assert_eq!(processor.get_neighbor_state(&point, 3, None).unwrap(), 3); // Upper Left
+        assert_eq!(processor.get_neighbor_state(&point, 7, None).unwrap(), 0); // Bottom Right
     }
 
     #[test]
     fn count_valid_neighbors() {
         let processor = blank_processor();
         // Getting neighbors for cell against the wall. there should be 5 valid neighbors.
-        let neighbors = processor.get_all_neighbors(Point::new(0, 2));
+        let neighbors = processor.get_all_neighbors(&Point::new(0, 2), None);
         let mut count = 0;
         for n in neighbors.iter() {
             match n {
@@ -266,4 +1138,614 @@ mod tests {
         }
         assert_eq!(count, 5);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn synchronous_blinker_oscillates() {
+        let mut p = processor_from(&format!("{}render 10 5 5\n", CONWAY), UpdateMode::Synchronous);
+        for y in 0..5 {
+            for x in 0..5 {
+                p.set_cell(if y == 2 && (1..4).contains(&x) { 1 } else { 2 }, x, y);
+            }
+        }
+
+        p.step();
+        assert_eq!(row(&p, 2), vec![2, 2, 1, 2, 2]);
+        assert_eq!(row(&p, 1), vec![2, 2, 1, 2, 2]);
+        p.step();
+        assert_eq!(row(&p, 2), vec![2, 1, 1, 1, 2]);
+        assert_eq!(p.generation, 2);
+    }
+
+    #[test]
+    fn in_place_modes_see_earlier_updates() {
+        // A 2 next to a 1 turns into a 1.
+        let src = "states 3\n2 ^1.1 _ _ 1\nrender 10 5 1\nseed\n0 0 1\n1 0 2\n2 0 2\n3 0 2\n4 0 2\n";
+
+        let mut p = processor_from(src, UpdateMode::Synchronous);
+        p.step();
+        assert_eq!(row(&p, 0), vec![1, 1, 2, 2, 2]);
+
+        let mut p = processor_from(src, UpdateMode::Sequential);
+        p.step();
+        assert_eq!(row(&p, 0), vec![1, 1, 1, 1, 1]);
+
+        let mut p = processor_from(src, UpdateMode::RandomOrder);
+        p.step();
+        assert_eq!(row(&p, 0)[1], 1);
+        assert!(row(&p, 0).iter().filter(|s| **s == 1).count() >= 2);
+    }
+
+    #[test]
+    fn in_place_cells_move_once_per_step() {
+        let mut p = processor_from("states 2\n1 ^0.0 _ r 1\nrender 10 5 1\nseed\n0 0 1\n", UpdateMode::Sequential);
+        p.step();
+        assert_eq!(row(&p, 0), vec![0, 1, 0, 0, 0]);
+
+        // Moving off the grid is blocked.
+        for _ in 0..5 {
+            p.step();
+        }
+        assert_eq!(row(&p, 0), vec![0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn margolus_blocks_alternate() {
+        // A 1 next to another 1 turns into a 2.
+        let mut p = processor_from("states 3\n1 ^1.1 _ _ 2\nrender 10 4 2\nseed\n1 0 1\n2 0 1\n", UpdateMode::Margolus);
+
+        // Cells 1 and 2 are in different blocks on even generations.
+        p.step();
+        assert_eq!(row(&p, 0), vec![0, 1, 1, 0]);
+        // and in the same block on odd generations.
+        p.step();
+        assert_eq!(row(&p, 0), vec![0, 2, 2, 0]);
+    }
+
+    #[test]
+    fn margolus_moves_stay_in_block() {
+        let mut p = processor_from("states 2\n1 ^0.0 _ r 1\nrender 10 4 2\nseed\n1 0 1\n", UpdateMode::Margolus);
+        p.step();
+        assert_eq!(row(&p, 0), vec![0, 1, 0, 0]);
+        p.step();
+        assert_eq!(row(&p, 0), vec![0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn same_seed_gives_same_generations() {
+        let src = "states 3\n1 ^0.0 _ ^ 1\n1 ^2.2 2 ^ 2\n2 ^1.1 _ ^ 1\nrender 10 16 16\n";
+        let run = |seed:u64| {
+            let mut p = processor_from(src, UpdateMode::RandomOrder);
+            p.reseed(seed);
+            p.gen_random_seed(vec![]).unwrap();
+            for _ in 0..20 {
+                p.step();
+            }
+            p.grid.clone()
+        };
+
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn random_seeds_need_a_living_state() {
+        let mut p = processor_from("states 1\nrender 1 4 4\n", UpdateMode::Synchronous);
+        p.gen_random_seed(vec![]).unwrap();
+        assert_eq!(p.count(0), 16);
+    }
+
+    #[test]
+    fn random_seeds_reject_unknown_states() {
+        let mut p = processor_from(&format!("{}render 1 4 4\n", CONWAY), UpdateMode::Synchronous);
+        assert!(p.gen_random_seed(vec![1, 5]).is_err());
+        assert!(p.gen_random_seed(vec![-1]).is_err());
+        // Nothing is set, so the first step does not run into a state without rules.
+        assert_eq!(p.count(0), 16);
+        p.step();
+    }
+
+    #[test]
+    fn random_moves_pick_empty_neighbors() {
+        // A cell in the corner, with two of its three neighbors taken.
+        let src = "states 3\n1 ^0.0 _ ^ 1\nrender 10 3 3\nseed\n0 0 1\n1 0 2\n0 1 2\n";
+        for seed in 0..10 {
+            let mut p = processor_from(src, UpdateMode::Synchronous);
+            p.reseed(seed);
+            p.step();
+            assert_eq!(p.grid.get(1, 1), 1);
+            assert_eq!(p.grid.get(0, 0), 0);
+        }
+    }
+
+    #[test]
+    fn blocked_random_moves_use_fallback() {
+        // The cell in the corner is surrounded and cannot move.
+        let src = "states 4\n1 ^0.0 3 _ 3\n1 ^0.0 2 ^ 2\nrender 10 2 2\nseed\n0 0 1\n1 0 2\n0 1 2\n1 1 2\n";
+        let corner = |blocked:BlockedMove| {
+            let mut p = processor_from(src, UpdateMode::Sequential);
+            p.world.blocked = blocked;
+            p.step();
+            p.grid.get(0, 0)
+        };
+
+        assert_eq!(corner(BlockedMove::Stay), 2);
+        assert_eq!(corner(BlockedMove::Skip), 1);
+        assert_eq!(corner(BlockedMove::Next), 3);
+    }
+
+    #[test]
+    fn dirty_steps_match_full_scans() {
+        // Conway on a dead background of state 2, plus cells that move and leave offspring.
+        let src = format!("{}3 ^1.1 2 r 3\n3 =0.1 _ d 3\nrender 10 120 90\n", CONWAY.replace("states 3", "states 4"));
+        let soup = |tracking:bool| {
+            let mut p = processor_from(&src, UpdateMode::Synchronous);
+            p.dirty_tracking = tracking;
+            p.reseed(3);
+            for y in 0..90 {
+                for x in 0..120 {
+                    p.set_cell(2, x, y);
+                }
+            }
+            // A sparse soup, so most steps only evaluate the cells near changes.
+            for i in 0..200 {
+                let (x, y) = (p.rand.gen_range(0..120), p.rand.gen_range(0..90));
+                p.set_cell(if i % 4 == 0 { 3 } else { 1 }, x, y);
+            }
+            p
+        };
+        let mut full = soup(false);
+        let mut dirty = soup(true);
+
+        for i in 0..60 {
+            // Edits between steps are tracked too.
+            if i == 30 {
+                full.set_cell(1, 5, 5);
+                dirty.set_cell(1, 5, 5);
+            }
+            let (full_stats, dirty_stats) = (full.step(), dirty.step());
+            assert_eq!(full.grid, dirty.grid, "generation {}", i);
+            assert_eq!(full_stats, dirty_stats, "generation {}", i);
+        }
+    }
+
+    #[test]
+    fn counted_rules_do_not_depend_on_dirty_tracking() {
+        // A still block, and a blinker far enough away to keep the dirty region small.
+        let src = format!("{}render 1 20 20\nseed\n2 2 1\n3 2 1\n2 3 1\n3 3 1\n14 15 1\n15 15 1\n16 15 1\n", CONWAY);
+        let run = |tracking:bool| {
+            let mut p = processor_from(&src, UpdateMode::Synchronous);
+            p.dirty_tracking = tracking;
+            p.count_rules = true;
+            for y in 0..20 {
+                for x in 0..20 {
+                    if p.get_cell(x, y) == 0 {
+                        p.set_cell(2, x, y);
+                    }
+                }
+            }
+            (0..4).map(|_| p.step()).collect::<Vec<_>>()
+        };
+        let stats = run(true);
+        assert_eq!(stats, run(false));
+
+        // Every cell of the block survives with three neighbors, every step.
+        let survive = processor_from(&src, UpdateMode::Synchronous).rule_set.state_rules(1).unwrap()[3].id;
+        for s in stats.iter() {
+            assert_eq!(s.fired.iter().find(|(id, _)| *id == survive).map(|(_, n)| *n), Some(4));
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn thread_count_does_not_change_results() {
+        let src = format!("{}3 ^1.1 2 ^ 3\n3 =0.1 _ d 3\nrender 10 200 200\n", CONWAY.replace("states 3", "states 4"));
+        let run = |threads:usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| {
+                let mut p = processor_from(&src, UpdateMode::Synchronous);
+                p.reseed(5);
+                for y in 0..200 {
+                    for x in 0..200 {
+                        p.set_cell(2, x, y);
+                    }
+                }
+                p.gen_random_seed(vec![1, 3]).unwrap();
+                for _ in 0..10 {
+                    p.step();
+                }
+                p.grid.clone()
+            })
+        };
+
+        assert_eq!(run(1), run(4));
+    }
+
+    #[test]
+    fn tables_match_the_interpreter() {
+        // Brian's Brain style rules with a third counted state, in every update mode.
+        let src = "states 4\n1 =2.2 2 _ 2\n2 ^0.0 3 _ 3\n3 ^0.0 _ _ 1\n1 ^3.3 2 _ 2\n1 =1.1 1 r 3\nrender 10 30 30\n";
+        for mode in [UpdateMode::Synchronous, UpdateMode::Sequential, UpdateMode::RandomOrder, UpdateMode::Margolus] {
+            let run = |compiled:bool| {
+                let mut p = processor_from(src, mode);
+                assert!(p.table.is_some());
+                if !compiled {
+                    p.table = None;
+                }
+                p.reseed(11);
+                p.gen_random_seed(vec![]).unwrap();
+                for _ in 0..25 {
+                    p.step();
+                }
+                p.grid.clone()
+            };
+            assert_eq!(run(true), run(false), "{:?}", mode);
+        }
+
+        // Explicit neighbors cannot be compiled.
+        assert!(processor_from("states 2\n1 2 _ _ 0\n", UpdateMode::Synchronous).table.is_none());
+    }
+
+    #[test]
+    fn unbounded_worlds_follow_moving_cells() {
+        let src = "states 2\n1 ^0.0 _ r 1\nrender 1 10 5\nseed\n0 0 1\nworld\nbounds unbounded\n";
+        let mut p = processor_from(src, UpdateMode::Synchronous);
+        for _ in 0..100 {
+            p.step();
+        }
+        assert_eq!(p.bounding_box(), Some((100, 0, 100, 0)));
+        // The frame is cut down to the chunks around the cell, not everything it passed through.
+        assert!(p.grid.width() <= 2 * super::CHUNK);
+
+        let src = "states 2\n1 ^0.0 _ l 1\nrender 1 10 5\nseed\n3 2 1\nworld\nbounds unbounded\n";
+        let mut p = processor_from(src, UpdateMode::Sequential);
+        for _ in 0..10 {
+            p.step();
+        }
+        assert_eq!(p.bounding_box(), Some((-7, 2, -7, 2)));
+    }
+
+    #[test]
+    fn unbounded_snapshots_cover_the_bounding_box() {
+        let src = "states 3\n1 ^0.0 _ r 1\n2 ^0.0 _ l 2\nrender 1 10 5\nseed\n5 0 1\n3 2 2\nworld\nbounds unbounded\n";
+        let mut p = processor_from(src, UpdateMode::Synchronous);
+        for _ in 0..20 {
+            p.step();
+        }
+        let snapshot = p.snapshot();
+
+        assert_eq!((snapshot.width, snapshot.height, snapshot.origin), (43, 3, (-17, 0)));
+        assert_eq!(snapshot.get(42, 0), 1);
+        assert_eq!(snapshot.get(0, 2), 2);
+        assert_eq!(snapshot.cells.iter().filter(|s| **s != 0).count(), 2);
+    }
+
+    #[test]
+    fn stepping_back_retraces_random_steps() {
+        let mut p = processor_from("states 3\n1 ^0.0 _ ^ 1\n2 ^1.1 _ _ 1\n", UpdateMode::RandomOrder);
+        p.reseed(3);
+        p.gen_random_seed(vec![1, 2]).unwrap();
+        let mut generations = vec![p.snapshot()];
+        for _ in 0..10 {
+            p.step();
+            generations.push(p.snapshot());
+        }
+
+        assert!(p.goto(3));
+        assert_eq!(p.snapshot(), generations[3]);
+        // The random number generator is rewound as well, so the same generations follow.
+        assert!(p.goto(10));
+        assert_eq!(p.snapshot(), generations[10]);
+
+        p.set_history_limit(4);
+        assert!(!p.goto(0));
+        assert!(p.step_back());
+        assert_eq!(p.snapshot(), generations[9]);
+    }
+
+    #[test]
+    fn stepping_back_restores_the_unbounded_frame() {
+        let src = "states 2\n1 ^0.0 _ l 1\nrender 1 10 5\nseed\n3 2 1\nworld\nbounds unbounded\n";
+        let mut p = processor_from(src, UpdateMode::Synchronous);
+        let start = p.snapshot();
+        for _ in 0..80 {
+            p.step();
+        }
+        assert!(p.goto(0));
+        assert_eq!(p.snapshot(), start);
+        assert!(!p.step_back());
+    }
+
+    #[test]
+    fn editing_past_the_unbounded_frame_drops_the_history() {
+        let src = "states 2\n1 ^0.0 _ r 1\nrender 1 10 5\nseed\n0 0 1\nworld\nbounds unbounded\n";
+        let mut p = processor_from(src, UpdateMode::Synchronous);
+        for _ in 0..3 {
+            p.step();
+        }
+        // Inside the frame, the history is kept.
+        p.set_cell(1, 5, 1);
+        assert_eq!(p.history_len(), 3);
+
+        p.set_cell(1, 3 * super::CHUNK, 2);
+        assert_eq!(p.history_len(), 0);
+        assert!(!p.step_back());
+        let edited = p.snapshot();
+        p.step();
+        assert!(p.step_back());
+        assert_eq!(p.snapshot(), edited);
+    }
+
+    #[test]
+    fn edits_start_cycle_detection_over() {
+        let mut p = processor_from("states 2\n1 ^0.0 _ r 1\nrender 1 10 1\nseed\n0 0 1\n", UpdateMode::Synchronous);
+        p.step();
+        // Putting the cell back makes the next generation the same as the last, which is not a still life.
+        p.clear();
+        assert_eq!(p.settled(), None);
+        p.set_cell(1, 0, 0);
+        p.step();
+        assert_eq!(p.settled(), None);
+
+        p.step();
+        p.clear();
+        p.try_set_cell(1, 1, 0).unwrap();
+        p.step();
+        assert_eq!(p.settled(), None);
+    }
+
+    #[test]
+    fn world_cells_keep_their_position_when_the_frame_moves() {
+        let src = "states 2\n1 =8.0 _ _ 1\nrender 1 10 5\nworld\nbounds unbounded\n";
+        let mut p = processor_from(src, UpdateMode::Synchronous);
+        p.set_world_cell(1, -40, -3).unwrap();
+        let origin = p.origin();
+        assert!(origin.0 <= -40 && origin.1 <= -3);
+        assert_eq!(p.get_world_cell(-40, -3).unwrap(), 1);
+
+        // Growing the frame the other way moves the origin, but not the cell.
+        p.set_world_cell(1, 3 * super::CHUNK as i64, 2).unwrap();
+        p.step();
+        p.set_world_cell(1, -5 * super::CHUNK as i64, 0).unwrap();
+        assert_ne!(p.origin(), origin);
+        assert_eq!(p.get_world_cell(-40, -3).unwrap(), 1);
+        assert_eq!(p.get_world_cell(-41, -3).unwrap(), 0);
+        assert_eq!(p.get_world_cell(-1000, 1000).unwrap(), 0);
+
+        // Bounded worlds start at (0, 0).
+        let mut p = processor_from("states 2\nrender 1 10 5\n", UpdateMode::Synchronous);
+        assert!(p.set_world_cell(1, -1, 0).is_err());
+        assert!(p.get_world_cell(0, -1).is_err());
+        p.set_world_cell(1, 9, 4).unwrap();
+        assert_eq!(p.get_cell(9, 4), 1);
+    }
+
+    #[test]
+    fn detects_how_simulations_settle() {
+        // A blinker, then a block, in a sea of dead Conway cells.
+        let mut p = processor_from(&format!("{}render 1 6 6\n", CONWAY), UpdateMode::Synchronous);
+        for y in 0..6 {
+            for x in 0..6 {
+                p.set_cell(if y == 2 && (1..4).contains(&x) { 1 } else { 2 }, x, y);
+            }
+        }
+        p.step();
+        assert_eq!(p.settled(), None);
+        p.step();
+        assert_eq!(p.settled(), Some(Settled::Cycle(2)));
+
+        for y in 0..6 {
+            for x in 0..6 {
+                p.set_cell(if (1..3).contains(&x) && (1..3).contains(&y) { 1 } else { 2 }, x, y);
+            }
+        }
+        p.step();
+        p.step();
+        assert_eq!(p.settled(), Some(Settled::StillLife));
+
+        // The hash kept up to date cell by cell matches hashing the grid from scratch.
+        let snapshot = p.snapshot();
+        let hash = snapshot.cells.iter().enumerate().fold(0u64, |h, (i, s)| h.wrapping_add(cell_hash((i % 6) as i64, (i / 6) as i64, *s)));
+        assert_eq!(p.grid_hash(), hash);
+
+        let mut p = processor_from("states 2\n1 ^0.0 _ _ 0\nrender 1 4 4\nseed\n1 1 1\n", UpdateMode::Sequential);
+        p.step();
+        assert_eq!(p.settled(), Some(Settled::Extinct));
+
+        // Random moves never count as settled, even when nothing can move.
+        let mut p = processor_from("states 2\n1 ^0.0 _ ^ 1\nrender 1 1 1\nseed\n0 0 1\n", UpdateMode::Synchronous);
+        p.step();
+        p.step();
+        assert_eq!(p.settled(), None);
+    }
+
+    #[test]
+    fn steps_report_what_happened() {
+        let mut p = processor_from("states 3\n1 ^0.0 _ r 1\n2 =0.1 _ _ 1\nrender 1 10 5\nseed\n0 0 1\n4 4 2\n", UpdateMode::Synchronous);
+        p.count_rules = true;
+        let stats = p.step();
+
+        // The moving cell leaves a dead cell behind, the other one changes state in place.
+        assert_eq!(stats.generation, 1);
+        assert_eq!(stats.counts, vec![48, 2, 0]);
+        assert_eq!((stats.births, stats.deaths, stats.moves), (1, 1, 1));
+        let ids:Vec<u64> = [1, 2].iter().map(|s| p.rule_set.state_rules(*s).unwrap()[0].id).collect();
+        assert_eq!(stats.fired, vec![(ids[0], 1), (ids[1], 1)]);
+        assert_eq!(p.counts(), &stats.counts[..]);
+    }
+
+    #[test]
+    fn observers_see_every_event() {
+        use std::sync::{Arc, Mutex};
+        use crate::{bio::BioRule, observer::SimulationObserver, stats::StepStats};
+
+        struct Log(Arc<Mutex<Vec<String>>>);
+        impl SimulationObserver for Log {
+            fn on_rule_fired(&mut self, point:Point, rule:&BioRule) {
+                self.0.lock().unwrap().push(format!("fired {} {} {}", point.x, point.y, rule.owner_state));
+            }
+            fn on_move(&mut self, from:Point, to:Point) {
+                self.0.lock().unwrap().push(format!("move {} {} {} {}", from.x, from.y, to.x, to.y));
+            }
+            fn on_cell_changed(&mut self, point:Point, old:i32, new:i32) {
+                self.0.lock().unwrap().push(format!("changed {} {} {} {}", point.x, point.y, old, new));
+            }
+            fn on_step_end(&mut self, stats:&StepStats) {
+                self.0.lock().unwrap().push(format!("end {}", stats.generation));
+            }
+        }
+
+        let mut p = processor_from("states 2\n1 ^0.0 _ r 1\nrender 1 10 5\nseed\n0 0 1\n", UpdateMode::Synchronous);
+        let log = Arc::new(Mutex::new(vec![]));
+        p.add_observer(Box::new(Log(log.clone())));
+        p.step();
+
+        assert_eq!(*log.lock().unwrap(), vec!["fired 0 0 1", "changed 0 0 1 0", "changed 1 0 0 1", "move 0 0 1 0", "end 1"]);
+        assert_eq!(p.take_observers().len(), 1);
+        p.step();
+        assert_eq!(log.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn edits_and_inspects_cells() {
+        let mut p = processor_from("states 3\n1 =3.1 1 _ 1\nrender 1 6 4\nseed\n1 1 2\n", UpdateMode::Synchronous);
+        assert_eq!(p.get_cell(1, 1), 2);
+        assert!(p.try_get_cell(6, 0).is_err());
+        assert!(p.try_set_cell(1, 0, 4).is_err());
+        assert!(p.try_set_cell(3, 0, 0).is_err());
+        p.try_set_cell(1, 5, 3).unwrap();
+        assert_eq!((p.count(1), p.count(2), p.count(0), p.count(7)), (1, 1, 22, 0));
+
+        p.resize(4, 2).unwrap();
+        assert_eq!(p.size(), (4, 2));
+        assert_eq!(p.iter_cells().filter(|c| c.2 != 0).collect::<Vec<_>>(), vec![(1, 1, 2)]);
+        assert_eq!(p.count(0), 7);
+
+        p.clear();
+        assert_eq!((p.count(0), p.active_cells().count(), p.grid_hash()), (8, 0, 0));
+
+        p.world.bounds = Bounds::Unbounded;
+        assert!(p.resize(8, 8).is_err());
+        assert_eq!(p.get_cell(100, 100), 0);
+    }
+
+    #[test]
+    fn rules_read_layers_under_the_grid() {
+        let src = "states 2\n1 ^0.0 _ r 1\n1 ^0.0 _ _ 1 terrain 0.wall\nrender 1 6 1\nseed\n0 0 1\nlayer terrain 3 soil wall water\nseed terrain\n2 0 water\n4 0 wall\n";
+        for mode in [UpdateMode::Synchronous, UpdateMode::Sequential] {
+            let mut p = processor_from(src, mode);
+            for _ in 0..5 {
+                p.step();
+            }
+            // The cell stops in front of the wall, and the water it passed over is still there.
+            assert_eq!(row(&p, 0), vec![0, 0, 0, 1, 0, 0]);
+            assert_eq!(p.layers()[0].get(2, 0), 2);
+        }
+
+        let mut p = processor_from(src, UpdateMode::Synchronous);
+        p.set_layer_cell(0, 1, 1, 0).unwrap();
+        p.step();
+        assert_eq!(row(&p, 0), vec![1, 0, 0, 0, 0, 0]);
+        assert!(p.set_layer_cell(0, 3, 1, 0).is_err());
+        assert!(p.set_layer_cell(1, 1, 1, 0).is_err());
+        assert!(p.rule_set.compile().is_none());
+    }
+
+    #[test]
+    fn rules_read_and_deposit_into_fields() {
+        let src = "states 3\n1 ^0.0 _ _ 1 field(pher)+1\n1 ^0.0 _ _ 2 field(pher)>1.2\nrender 1 3 1\nseed\n1 0 1\nworld\nfield pher 0 0.5\nfield smell 0.5 0\n";
+        let mut p = processor_from(src, UpdateMode::Synchronous);
+        p.step();
+        p.step();
+        assert_eq!(row(&p, 0), vec![0, 1, 0]);
+        assert_eq!(p.fields()[0].values(), &[0.0, 1.5, 0.0]);
+
+        // The cell reads the amount it built up, while the amount keeps decaying.
+        p.step();
+        assert_eq!(row(&p, 0), vec![0, 2, 0]);
+        assert_eq!(p.fields()[0].get(1, 0), 0.75);
+        assert!(p.step_back());
+        assert_eq!((row(&p, 0), p.fields()[0].get(1, 0)), (vec![0, 1, 0], 1.5));
+
+        p.deposit(1, 0, 0, 4.0).unwrap();
+        p.step();
+        assert_eq!(p.fields()[1].values(), &[3.5, 0.5, 0.0]);
+        assert!(p.step_back());
+        assert_eq!(p.fields()[1].values(), &[4.0, 0.0, 0.0]);
+        assert_eq!(p.fields()[0].get(1, 0), 1.5);
+        p.step();
+        assert!(p.deposit(1, 3, 0, 1.0).is_err());
+        assert!(p.deposit(2, 0, 0, 1.0).is_err());
+        assert!(p.rule_set.compile().is_none());
+    }
+
+    #[test]
+    fn elementary_rules_draw_space_time_diagrams() {
+        let mut p = processor_from("states 2\nrender 1 7 3\nseed\n3 0 1\nworld\nrule 30\n", UpdateMode::Synchronous);
+        assert_eq!(p.size(), (7, 1));
+        p.step();
+        p.step();
+        assert_eq!(p.space_time(), vec![
+            vec![0, 0, 0, 1, 0, 0, 0],
+            vec![0, 0, 1, 1, 1, 0, 0],
+            vec![0, 1, 1, 0, 0, 1, 0]
+        ]);
+
+        // Only as many rows as the render grid is high are kept, and stepping back drops the last one.
+        p.step();
+        assert_eq!(p.space_time()[2], vec![1, 1, 0, 1, 1, 1, 1]);
+        assert!(p.step_back());
+        assert_eq!(p.space_time(), vec![vec![0, 0, 1, 1, 1, 0, 0], vec![0, 1, 1, 0, 0, 1, 0]]);
+    }
+
+    #[test]
+    fn one_dimensional_rules_match_the_rule_number() {
+        // Rule 30 written as rules, with 1 for off and 2 for on since rules cannot turn state 0 into anything.
+        let rules = "states 3 void off on\noff =1.on _ _ on\non 4.on _ _ off\nrender 1 31 1\nworld\ndimensions 1\n";
+        let mut written = processor_from(rules, UpdateMode::Synchronous);
+        let mut numbered = processor_from("states 2\nrender 1 31 1\nworld\nrule 30\n", UpdateMode::Synchronous);
+        let mut rng = StdRng::seed_from_u64(30);
+        for x in 0..31 {
+            let on = rng.gen_bool(0.5);
+            written.set_cell(if on { 2 } else { 1 }, x, 0);
+            numbered.set_cell(on as i32, x, 0);
+        }
+        for _ in 0..20 {
+            written.step();
+            numbered.step();
+            let on:Vec<i32> = row(&written, 0).iter().map(|s| (*s == 2) as i32).collect();
+            assert_eq!(on, row(&numbered, 0));
+        }
+    }
+
+    #[test]
+    fn elementary_examples_grow_from_one_cell() {
+        let example = |name:&str| {
+            let src = std::fs::read_to_string(format!("{}/examples/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap();
+            processor_from(&src, UpdateMode::Synchronous)
+        };
+        let mut p = example("rule30.cell");
+        for _ in 0..3 {
+            p.step();
+        }
+        let rows:Vec<Vec<i32>> = p.space_time().iter().map(|r| r[57..64].to_vec()).collect();
+        assert_eq!(rows, vec![
+            vec![0, 0, 0, 1, 0, 0, 0],
+            vec![0, 0, 1, 1, 1, 0, 0],
+            vec![0, 1, 1, 0, 0, 1, 0],
+            vec![1, 1, 0, 1, 1, 1, 1]
+        ]);
+        assert_eq!(p.count(1), 6);
+
+        // The rule 110 example is run with -fill 1, which leaves the seeded cell on.
+        let mut p = example("rule110.cell");
+        for x in 0..120 {
+            p.set_cell(1, x, 0);
+        }
+        for _ in 0..3 {
+            p.step();
+        }
+        let rows:Vec<Vec<i32>> = p.space_time().iter().map(|r| r[117..].to_vec()).collect();
+        assert_eq!(rows, vec![vec![1, 1, 1, 2], vec![1, 1, 2, 2], vec![1, 2, 2, 2], vec![2, 2, 1, 2]]);
+        assert_eq!(p.count(2), 3);
+    }
+}
@@ -1,51 +1,136 @@
 use macroquad::prelude::*;
 
-use crate::processor::Processor;
+use crate::{config::Bounds, grid::CellState, processor::Processor};
 
 
+/// Draws the grid and steps the processor on a timer.
+/// Space pauses, the left and right arrow keys step back and forward through the generations.
+/// F shows each field as a heatmap in turn, then none.
 pub struct SimpleRenderer {
     tick_rate:f32,
-    timer:f32
+    timer:f32,
+    paused:bool,
+    heatmap:Option<usize>   // Field drawn as a heatmap, if any
 }
 
 impl SimpleRenderer {
     pub fn new(tick_rate:f32) -> SimpleRenderer {
-        SimpleRenderer { tick_rate: tick_rate, timer:0.0 }
+        SimpleRenderer { tick_rate: tick_rate, timer:0.0, paused:false, heatmap:None }
     }
 
-    pub async fn update(&mut self, processor:&mut Processor) {
+    pub async fn update<S:CellState>(&mut self, processor:&mut Processor<S>) {
         clear_background(Color::from_rgba(222, 222, 222, 255));
 
-        let S:f32 = processor.render_rules.cell_size as f32;
+        let mut S:f32 = processor.render_rules.cell_size as f32;
+        let unbounded = processor.world.bounds == Bounds::Unbounded;
 
-        for p in &processor.cell_map {
-            let state = p.1.to_owned();
-            let pos = p.0.to_owned();
-            // Color stuff
-            let color_data = processor.render_rules.get_color(state);
-            let color_bytes = color_data.to_be_bytes();
-            //red_ln!("Color: {}, {}, {}, {}", color_bytes[0], color_bytes[1], color_bytes[2], color_bytes[3]);
-            let color = Color::from_rgba(color_bytes[0], color_bytes[1], color_bytes[2], color_bytes[3]);
-            /*if state == 1 {
-                color = YELLOW;
-            }*/
-            draw_rectangle(pos.x as f32 * S, pos.y as f32 * S, S, S, color);
+        // An unbounded world is drawn from the corner of its bounding box, shrunk to fit in the window.
+        let (mut left, mut top) = (0, 0);
+        if let (true, Some((l, t, r, b))) = (unbounded, processor.bounding_box()) {
+            let (ox, oy) = processor.origin();
+            left = l - ox;
+            top = t - oy;
+            S = S.min(screen_width() / (r - l + 1) as f32).min(screen_height() / (b - t + 1) as f32);
         }
 
-        let wn = processor.render_rules.grid_width+1;
-        for i in 0..wn {
-            let fi = i as f32;
-            draw_line(fi * S, 0.0, fi * S, wn as f32 * S, 1.0, BLACK);
+        // Layers go underneath the grid, each over the ones declared before it. Colors with alpha let the ones below show through.
+        let (ox, oy) = processor.origin();
+        for layer in processor.layers() {
+            for (x, y, state) in layer.cells() {
+                if let Some(c) = layer.get_color(state) {
+                    let [r, g, b, a] = c.to_be_bytes();
+                    draw_rectangle((x as i64 - ox - left) as f32 * S, (y as i64 - oy - top) as f32 * S, S, S, Color::from_rgba(r, g, b, a));
+                }
+            }
         }
-        let wh = processor.render_rules.grid_height+1;
-        for i in 0..wh {
-            let fi = i as f32;
-            draw_line(0.0, fi * S, wh as f32 * S, fi * S, 1.0, BLACK);
+
+        // The heatmap goes over the layers, from clear at 0 to a strong red at the largest amount in the field.
+        if let Some(field) = self.heatmap.and_then(|f| processor.fields().get(f)) {
+            let max = field.max();
+            if max > 0.0 {
+                for (i, v) in field.values().iter().enumerate() {
+                    if *v > 0.0 {
+                        let (x, y) = ((i % field.width()) as i64, (i / field.width()) as i64);
+                        let a = (v / max * 200.0) as u8;
+                        draw_rectangle((x - ox - left) as f32 * S, (y - oy - top) as f32 * S, S, S, Color::from_rgba(230, 40, 20, a));
+                    }
+                }
+            }
+        }
+
+        // A one-dimensional world is drawn as a space-time diagram, a row per generation with the current one at the bottom.
+        if processor.world.dimensions == 1 {
+            for (y, row) in processor.space_time().iter().enumerate() {
+                for (x, state) in row.iter().enumerate().filter(|(_, s)| **s != 0) {
+                    let [r, g, b, a] = processor.render_rules.get_color(*state).to_be_bytes();
+                    draw_rectangle(x as f32 * S, y as f32 * S, S, S, Color::from_rgba(r, g, b, a));
+                }
+            }
+        }
+        else {
+            for (x, y, state) in processor.active_cells() {
+                // Color stuff
+                let color_data = processor.render_rules.get_color(state);
+                let color_bytes = color_data.to_be_bytes();
+                //red_ln!("Color: {}, {}, {}, {}", color_bytes[0], color_bytes[1], color_bytes[2], color_bytes[3]);
+                let color = Color::from_rgba(color_bytes[0], color_bytes[1], color_bytes[2], color_bytes[3]);
+                /*if state == 1 {
+                    color = YELLOW;
+                }*/
+                draw_rectangle((x as i64 - left) as f32 * S, (y as i64 - top) as f32 * S, S, S, color);
+            }
+        }
+
+        if !unbounded {
+            let wn = processor.render_rules.grid_width+1;
+            for i in 0..wn {
+                let fi = i as f32;
+                draw_line(fi * S, 0.0, fi * S, wn as f32 * S, 1.0, BLACK);
+            }
+            let wh = processor.render_rules.grid_height+1;
+            for i in 0..wh {
+                let fi = i as f32;
+                draw_line(0.0, fi * S, wh as f32 * S, fi * S, 1.0, BLACK);
+            }
+        }
+
+        let mut status = match processor.settled() {
+            Some(s) => format!("Generation {} ({})", processor.generation, s),
+            None => format!("Generation {}", processor.generation)
+        };
+        if let (true, Some(reason)) = (self.paused, processor.break_reason()) {
+            status = format!("{} - paused, {}", status, reason);
+        }
+        if let Some(field) = self.heatmap.and_then(|f| processor.fields().get(f)) {
+            status = format!("{} - field {} (max {:.2})", status, field.name, field.max());
+        }
+        draw_text(&status, 8.0, screen_height() - 8.0, 24.0, BLACK);
+
+        // Moving through the generations by hand pauses the simulation.
+        if is_key_pressed(KeyCode::Space) {
+            self.paused = !self.paused;
+        }
+        if is_key_pressed(KeyCode::F) {
+            self.heatmap = match self.heatmap {
+                None if !processor.fields().is_empty() => Some(0),
+                Some(f) if f + 1 < processor.fields().len() => Some(f + 1),
+                _ => None
+            };
+        }
+        if is_key_pressed(KeyCode::Left) {
+            self.paused = true;
+            processor.step_back();
+        }
+        if is_key_pressed(KeyCode::Right) {
+            self.paused = true;
+            processor.step();
         }
 
         self.timer += get_frame_time();
-        if self.timer > self.tick_rate {
+        if self.timer > self.tick_rate && !self.paused {
             processor.step();
+            // A breakpoint that was hit pauses until Space is pressed.
+            self.paused = processor.break_reason().is_some();
             self.timer = 0.0;
         }
         
@@ -0,0 +1,109 @@
+use std::io::{self, Write};
+
+/// What happened during one step.
+#[derive(Clone, PartialEq, Debug)]
+pub struct StepStats {
+    pub generation:u64,             // Generation the step produced
+    pub counts:Vec<usize>,          // Number of cells in each state after the step
+    pub births:usize,               // Cells that went from the dead state to another state
+    pub deaths:usize,               // Cells that went to the dead state
+    pub moves:usize,                // Rules applied that moved their cell somewhere else
+    pub fired:Vec<(u64, usize)>     // Every rule's id, in rule set order, with how many cells applied it. Empty unless the processor counts rules.
+}
+
+/// How stats records are written out.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StatsFormat {
+    Csv,            // A header line, then one line of comma separated values per step.
+    JsonLines       // One JSON object per step.
+}
+
+impl StatsFormat {
+    /// Picks the format from a file name: CSV for .csv files, JSON Lines for anything else.
+    pub fn from_path(path:&str) -> StatsFormat {
+        if path.ends_with(".csv") { StatsFormat::Csv } else { StatsFormat::JsonLines }
+    }
+}
+
+/// Streams stats records to a writer, one line per step.
+pub struct StatsWriter<W:Write> {
+    out:W,
+    format:StatsFormat,
+    started:bool
+}
+
+impl<W:Write> StatsWriter<W> {
+    pub fn new(out:W, format:StatsFormat) -> StatsWriter<W> {
+        StatsWriter { out, format, started:false }
+    }
+
+    /// Writes one record. The CSV header is taken from the first record, so every record should come from the same rule set.
+    pub fn write(&mut self, stats:&StepStats) -> io::Result<()> {
+        match self.format {
+            StatsFormat::Csv => {
+                if !self.started {
+                    let states = (0..stats.counts.len()).map(|s| format!(",state_{}", s)).collect::<String>();
+                    let rules = stats.fired.iter().map(|(h, _)| format!(",rule_{:016x}", h)).collect::<String>();
+                    writeln!(self.out, "generation,births,deaths,moves{}{}", states, rules)?;
+                }
+                let counts = stats.counts.iter().map(|c| format!(",{}", c)).collect::<String>();
+                let fired = stats.fired.iter().map(|(_, n)| format!(",{}", n)).collect::<String>();
+                writeln!(self.out, "{},{},{},{}{}{}", stats.generation, stats.births, stats.deaths, stats.moves, counts, fired)?;
+            },
+            StatsFormat::JsonLines => {
+                let fired:serde_json::Map<String, serde_json::Value> = stats.fired.iter().map(|(id, n)| (format!("{:016x}", id), (*n).into())).collect();
+                let record = serde_json::json!({
+                    "generation": stats.generation,
+                    "births": stats.births,
+                    "deaths": stats.deaths,
+                    "moves": stats.moves,
+                    "counts": stats.counts,
+                    "fired": fired
+                });
+                writeln!(self.out, "{}", record)?;
+            }
+        };
+        self.started = true;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StatsFormat, StatsWriter, StepStats};
+
+    fn stats(generation:u64) -> StepStats {
+        StepStats { generation, counts:vec![5, 3], births:2, deaths:1, moves:0, fired:vec![(0xab, 2), (0xcd, 1)] }
+    }
+
+    #[test]
+    fn writes_csv_with_one_header() {
+        let mut w = StatsWriter::new(vec![], StatsFormat::from_path("out.csv"));
+        w.write(&stats(1)).unwrap();
+        w.write(&stats(2)).unwrap();
+        let text = String::from_utf8(w.into_inner()).unwrap();
+
+        assert_eq!(text.lines().collect::<Vec<&str>>(), vec![
+            "generation,births,deaths,moves,state_0,state_1,rule_00000000000000ab,rule_00000000000000cd",
+            "1,2,1,0,5,3,2,1",
+            "2,2,1,0,5,3,2,1"
+        ]);
+    }
+
+    #[test]
+    fn writes_one_json_object_per_line() {
+        let mut w = StatsWriter::new(vec![], StatsFormat::from_path("out.jsonl"));
+        w.write(&stats(7)).unwrap();
+        let text = String::from_utf8(w.into_inner()).unwrap();
+        let record:serde_json::Value = serde_json::from_str(text.trim_end()).unwrap();
+
+        assert_eq!(record["generation"], 7);
+        assert_eq!(record["counts"][1], 3);
+        assert_eq!(record["fired"]["00000000000000ab"], 2);
+        assert_eq!(record["fired"]["00000000000000cd"], 1);
+    }
+}
@@ -1,7 +1,7 @@
 /**
  * Matthew Kleitz, 2021
  * -- Tokens --
- * states [0-9] . _ * & ^ @ render r l u d 
+ * states seed world layer [0-9] . _ * & ^ @ ( ) < > + render r l u d <name> <color>
  */
 use std::io;
 use std::fmt;
@@ -9,7 +9,7 @@ use std::fs;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenType {
-    Number,         // Decimal or Hex Number
+    Number,         // Decimal Number
     Dot,            // .
     Null,           // _
     All,            // *
@@ -17,8 +17,16 @@ pub enum TokenType {
     Any,            // ^
     Equal,          // =
     Absorb,         // @
-    Label,          // states, render
+    LParen,         // (
+    RParen,         // )
+    Less,           // <
+    Greater,        // >
+    Plus,           // +
+    Label,          // states, render, seed, world, layer
     Direction,      // l, r, u, d
+    Ident,          // State names
+    Color,          // RRGGBB, RRGGBBAA or #RRGGBB in the render and layer sections
+    Comment,        // # ...
     Newline,        // \n
     Space,
     Tab,
@@ -34,32 +42,48 @@ impl fmt::Display for TokenType {
 pub struct Token {
     pub ttype:TokenType,
     pub lexeme:String,
-    pub line:usize
+    pub line:usize,
+    pub col:usize
 }
 
 impl Token {
-    fn new(tt:TokenType, lex:String, line:usize) -> Token {
-        Token { ttype:tt, lexeme:lex, line:line }
+    fn new(tt:TokenType, lex:String, line:usize, col:usize) -> Token {
+        Token { ttype:tt, lexeme:lex, line, col }
     }
 }
 
+/// The section of the source file the tokenizer is in. Words are read differently in each section.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Mode {
+    Rules,
+    Render,
+    Seed,
+    World,
+    Layer
+}
+
 /// The tokenizer will parse and tokenize a cell-machine source file.
 /// Give the input string when creating a new tokenizer and then call parse().
 /// If the tokenization process is successful, you can retrieve the results from the tokens field.
 pub struct Tokenizer {
     pub tokens:Vec<Token>,      // Stores tokens during parsing process.
-    input:String,           // Inputted code.
+    input:Vec<char>,        // Inputted code.
     char_index:usize,       // Index of current char.
     cur_char:char,          // What char the tokenizer is currently looking at.
     word_stack:Vec<char>,   // Used to help parse user defined token words such as numbers.
-    cur_line:usize          // Current line of input file.
+    cur_line:usize,         // Current line of input file.
+    cur_col:usize,          // Column of the current char, starting at 1.
+    token_col:usize,        // Column where the token being parsed started.
+    mode:Mode,              // Section of the file being read.
+    line_words:usize,       // Number of words read on the current line.
+    line_label:bool         // Set true if the current line starts with a label.
 }
 
 impl Tokenizer {
     /// Creates a new tokenizer that is primed to process given input data.
     pub fn new(inp:String) -> Tokenizer {
-        let first = inp.chars().next().unwrap();
-        Tokenizer { tokens:vec![], input:inp, char_index:0, cur_char:first, word_stack:vec![], cur_line:1 }
+        let first = inp.chars().next().unwrap_or('\0');
+        Tokenizer { tokens:vec![], input:inp.chars().collect(), char_index:0, cur_char:first, word_stack:vec![], cur_line:1, cur_col:1, token_col:1, mode:Mode::Rules, line_words:0, line_label:false }
     }
 
     pub fn new_from_file(path:String) -> Tokenizer {
@@ -68,9 +92,17 @@ impl Tokenizer {
         Tokenizer::new(data)
     }
 
+    /// Returns the line and column of the token the tokenizer stopped at. Useful for locating errors.
+    pub fn position(&self) -> (usize, usize) {
+        (self.cur_line, self.token_col)
+    }
+
     /// Creates and stores a token in the tokenizer's list.
     fn add_token(&mut self, t:TokenType, l:String) {
-        let token = Token::new(t, l, self.cur_line);
+        if !matches!(t, TokenType::Space | TokenType::Tab | TokenType::Newline | TokenType::Comment) {
+            self.line_words += 1;
+        }
+        let token = Token::new(t, l, self.cur_line, self.token_col);
         self.tokens.push(token);
     }
     
@@ -78,8 +110,13 @@ impl Tokenizer {
     /// If the end input is reached, this function will return false.
     fn advance(&mut self) -> bool {
         self.char_index += 1;
+        if self.cur_char == '\n' {
+            self.cur_col = 1;
+        } else {
+            self.cur_col += 1;
+        }
         if self.char_index < self.input.len() {
-            self.cur_char = self.input.chars().nth(self.char_index).unwrap();
+            self.cur_char = self.input[self.char_index];
             return true;
         }
         false
@@ -90,7 +127,7 @@ impl Tokenizer {
     fn peek_next(&self) -> Option<char> {
         let peek_index = self.char_index + 1;
         if peek_index < self.input.len() {
-            return Some(self.input.chars().nth(peek_index).unwrap());
+            return Some(self.input[peek_index]);
         }
         None
     }
@@ -98,149 +135,170 @@ impl Tokenizer {
     /// Call this function to begin the tokenizer. If the tokenizer is successful, this will return Ok()
     pub fn start(&mut self) -> io::Result<()> {
         self.tokens.clear();
-        self.cur_char = self.input.chars().nth(0).unwrap();
         self.cur_line = 1;
+        self.cur_col = 1;
         self.char_index = 0;
-        //self.advance();
+        self.mode = Mode::Rules;
+        self.line_words = 0;
+        self.line_label = false;
+        if self.input.is_empty() {
+            self.tokens.push(Token::new(TokenType::EOF, "EOF".to_string(), self.cur_line, self.cur_col));
+            return Ok(());
+        }
+        self.cur_char = self.input[0];
         let parse_result = self.parse();
 
         // Add an EOF token if parse was success
         match parse_result {
-            Ok(o) => self.tokens.push(Token::new(TokenType::EOF, "EOF".to_string(), self.cur_line)),
+            Ok(o) => self.tokens.push(Token::new(TokenType::EOF, "EOF".to_string(), self.cur_line, self.cur_col)),
             _ => ()
         };
         parse_result
     }
 
-    /// Main function of the recursive descent parser.
-    
+    /// Main function of the tokenizer. Reads every char of the input, stopping at the first invalid symbol.
     fn parse(&mut self) -> io::Result<()> {
-        match self.cur_char {
-            '_' => self.add_token(TokenType::Null, String::from("λ")),
-            '.' => self.add_token(TokenType::Dot, String::from(".")),
-            '*' => self.add_token(TokenType::All, String::from("*")),
-            '^' => self.add_token(TokenType::Any, String::from("^")),
-            '=' => self.add_token(TokenType::Equal, String::from("=")),
-            '&' => self.add_token(TokenType::Link, String::from("&")),
-            '@' => self.add_token(TokenType::Absorb, String::from("@")),
-            'l' => self.add_token(TokenType::Direction, String::from("l")),
-            'u' => self.add_token(TokenType::Direction, String::from("u")),
-            'd' => self.add_token(TokenType::Direction, String::from("d")),
-            ' ' => self.add_token(TokenType::Space, String::from("~")),
-            '\t' => self.add_token(TokenType::Tab, String::from("\\t")),
-            '\n' => {
-                self.add_token(TokenType::Newline, String::from("\\n"));
-                self.cur_line += 1;
-            },
-            '\r' => (),
-            '#' => {
-                self.advance();
-                self.parse_comment();
-            },
-            's' => {
-                let result = self.parse_keyword(String::from("states"));
-                if !result {
-                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unexpected Symbol {} when trying to parse 'states' label on line {}. Aborting Parse.", self.cur_char, self.cur_line)));
-                }
-            },
-            'r' => {
-                let peek = self.peek_next().expect("Unexpected end of file after 'r'");
-                if peek == 'e' {
-                    let result = self.parse_keyword(String::from("render"));
-                    if !result {
-                        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unexpected Symbol {} when trying to parse 'render' label on line {}. Aborting Parse.", self.cur_char, self.cur_line)));
+        loop {
+            self.token_col = self.cur_col;
+            match self.cur_char {
+                '_' => self.add_token(TokenType::Null, String::from("λ")),
+                '.' => self.add_token(TokenType::Dot, String::from(".")),
+                '*' => self.add_token(TokenType::All, String::from("*")),
+                '^' => self.add_token(TokenType::Any, String::from("^")),
+                '=' => self.add_token(TokenType::Equal, String::from("=")),
+                '&' => self.add_token(TokenType::Link, String::from("&")),
+                '@' => self.add_token(TokenType::Absorb, String::from("@")),
+                '(' => self.add_token(TokenType::LParen, String::from("(")),
+                ')' => self.add_token(TokenType::RParen, String::from(")")),
+                '<' => self.add_token(TokenType::Less, String::from("<")),
+                '>' => self.add_token(TokenType::Greater, String::from(">")),
+                '+' => self.add_token(TokenType::Plus, String::from("+")),
+                ' ' => self.add_token(TokenType::Space, String::from("~")),
+                '\t' => self.add_token(TokenType::Tab, String::from("\\t")),
+                '\n' => {
+                    self.add_token(TokenType::Newline, String::from("\\n"));
+                    self.cur_line += 1;
+                    self.line_words = 0;
+                    self.line_label = false;
+                },
+                '\r' => (),
+                '#' if self.expects_color() => {
+                    self.word_stack.push('#');
+                    self.parse_word();
+                    self.parse_color()?;
+                },
+                '#' => {
+                    self.parse_comment();
+                    let text:String = self.word_stack.iter().collect();
+                    self.add_token(TokenType::Comment, text);
+                    self.word_stack.clear();
+                },
+                c if c.is_ascii_alphanumeric() => {
+                    self.word_stack.push(c);
+                    self.parse_word();
+                    if self.expects_color() {
+                        self.parse_color()?;
+                    } else {
+                        self.classify_word()?;
                     }
-                }
-                else {
-                    self.add_token(TokenType::Direction, String::from("r"));
-                }
-            },
-            _ => {
-                if self.cur_char.is_digit(16) {
-                    self.word_stack.push(self.cur_char);
-                    self.parse_number();
-                }
-                else {
+                },
+                _ => {
                     return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unexpected Symbol {} on line {}. Aborting Parse.\n", self.cur_char, self.cur_line)));
                 }
+            };
+            if !self.advance() {
+                return Ok(());
             }
-        };
-        if self.advance() {
-            self.parse().expect("PARSE FAIL");
         }
-        
-        Ok(())
     }
 
-    /// Parses a number token.
-    /// This function assumes that the first digit of the number was placed in self.word_stack already.
-    fn parse_number(&mut self) {
-        let peek = self.peek_next();
-        let mut is_dig = false;
-        match peek {
-            Some(c) => is_dig = c.is_digit(16),
-            None => ()
-        };
-        if is_dig {
+    /// Returns true when the word being read is the color of a 'state color' line in the render or a layer section.
+    fn expects_color(&self) -> bool {
+        matches!(self.mode, Mode::Render | Mode::Layer) && self.line_words == 1 && !self.line_label
+    }
+
+    /// Reads the rest of a word made of letters, digits and underscores into self.word_stack.
+    /// This function assumes that the first char of the word was placed in self.word_stack already.
+    fn parse_word(&mut self) {
+        while let Some(c) = self.peek_next() {
+            if !c.is_ascii_alphanumeric() && c != '_' {
+                break;
+            }
             self.advance();
             self.word_stack.push(self.cur_char);
-            self.parse_number();
-        }
-        
-        if self.word_stack.len() > 0 {
-            let lex = self.word_stack.iter().collect();
-            self.add_token(TokenType::Number, lex);
-            self.word_stack.clear();
         }
     }
 
-    /// Attempts to tokenize the 'states' keyword.
-    /// Will return a bool value indicating the success of tokenizing.
-    #[deprecated]
-    fn parse_kw_states(&mut self) -> bool {
-        let key:Vec<char> = vec!['s', 't', 'a', 't', 'e', 's'];
-        for i in 0..key.len() {
-            if key[i] != self.cur_char {
-                return false; // failed to parse whole word
-            }
-            if i != key.len() - 1 {
-                self.advance();
+    /// Turns the word in self.word_stack into a keyword, direction, identifier or decimal number token.
+    /// Keywords switch the tokenizer into the mode of their section.
+    fn classify_word(&mut self) -> io::Result<()> {
+        let word:String = self.word_stack.drain(..).collect();
+
+        if word.starts_with(|c:char| c.is_ascii_digit()) {
+            if !word.chars().all(|c| c.is_ascii_digit()) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid number '{}' on line {}. Aborting Parse.", word, self.cur_line)));
             }
+            self.add_token(TokenType::Number, word);
+            return Ok(());
         }
-        self.add_token(TokenType::Label, String::from("states"));
-        true
-    }
 
-    fn parse_keyword(&mut self, kw:String) -> bool {
-        let key:Vec<char> = kw.chars().collect();
+        let mode = match word.as_ref() {
+            "states" => Some(Mode::Rules),
+            "render" => Some(Mode::Render),
+            "seed" => Some(Mode::Seed),
+            "world" => Some(Mode::World),
+            "layer" => Some(Mode::Layer),
+            _ => None
+        };
+        match mode {
+            Some(m) => {
+                self.mode = m;
+                self.line_label = true;
+                self.add_token(TokenType::Label, word);
+            },
+            None if word.len() == 1 && "lrud".contains(&word) => self.add_token(TokenType::Direction, word),
+            None => self.add_token(TokenType::Ident, word)
+        };
+        Ok(())
+    }
 
-        for i in 0..key.len() {
-            if key[i] != self.cur_char {
-                return false; // failed to parse whole word
-            }
-            if i != key.len() - 1 {
-                self.advance();
-            }
+    /// Turns the word in self.word_stack into a color token.
+    /// Colors are written as RRGGBB or RRGGBBAA hex digits, optionally starting with '#'.
+    fn parse_color(&mut self) -> io::Result<()> {
+        let word:String = self.word_stack.drain(..).collect();
+        if parse_color(&word).is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid color '{}' on line {}. Expected RRGGBB, RRGGBBAA or #RRGGBB. Aborting Parse.", word, self.cur_line)));
         }
-        self.add_token(TokenType::Label, kw);
-        true
+        self.add_token(TokenType::Color, word);
+        Ok(())
     }
 
-    /// Handles the parsing of comments. Advance past every char until a newline is reached.
+    /// Handles the parsing of comments. Advance past every char until a newline is reached,
+    /// collecting the comment text (without the leading '#') in self.word_stack.
     fn parse_comment(&mut self) {
-        let peek = self.peek_next();
-        match peek {
-            Some(c) => {
-                if c != '\n' && c != '\0' && c != '\r' {
-                    self.advance();
-                    self.parse_comment();
-                }
-            },
-            None => ()
+        while let Some(c) = self.peek_next() {
+            if c == '\n' || c == '\0' || c == '\r' {
+                break;
+            }
+            self.advance();
+            self.word_stack.push(self.cur_char);
         }
     }
 }
 
+/// Returns the RGBA value of a color literal. Colors without an alpha channel are opaque.
+pub fn parse_color(lexeme:&str) -> Option<u32> {
+    let hex = lexeme.strip_prefix('#').unwrap_or(lexeme);
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    match hex.len() {
+        6 => u32::from_str_radix(hex, 16).ok().map(|c| (c << 8) | 0xFF),
+        8 => u32::from_str_radix(hex, 16).ok(),
+        _ => None
+    }
+}
+
 pub fn print_tokens(tokens:&Vec<Token>) {
     //println!("Input:\n----------\n{}\n\n\n~~TOKENS~~\n", self.input);
     println!("{}\t\t{}\t\t\t{}\t\t{}\n{:->64}", "Type", "Lexeme", "Line #", "Index", "");
@@ -266,12 +324,43 @@ mod tests {
     fn simple_tokenize() {
         let mut t = Tokenizer::new("states 2\n".to_string());
         let tokens = vec![
-            Token::new(TokenType::Label, "states".to_string(), 1), 
-            Token::new(TokenType::Space, "~".to_string(), 1), 
-            Token::new(TokenType::Number, "2".to_string(), 1),
-            Token::new(TokenType::Newline, "\\n".to_string(), 1)];
+            Token::new(TokenType::Label, "states".to_string(), 1, 1), 
+            Token::new(TokenType::Space, "~".to_string(), 1, 7), 
+            Token::new(TokenType::Number, "2".to_string(), 1, 8),
+            Token::new(TokenType::Newline, "\\n".to_string(), 1, 9)];
         t.parse().unwrap();
 
         assert_eq!(t.tokens, tokens);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn comment_text_is_kept() {
+        let mut t = Tokenizer::new("2 # birth\n".to_string());
+        t.parse().unwrap();
+
+        assert_eq!(t.tokens[2], Token::new(TokenType::Comment, " birth".to_string(), 1, 3));
+        assert_eq!(t.tokens[3].ttype, TokenType::Newline);
+    }
+
+    #[test]
+    fn lexes_lowercase_colors_in_render_section() {
+        let mut t = Tokenizer::new("render 8 20 20\n0 dd00ffaa\nlive #ff0000\n".to_string());
+        t.start().unwrap();
+        let types:Vec<TokenType> = t.tokens.iter().filter(|t| t.ttype != TokenType::Space).map(|t| t.ttype.clone()).collect();
+
+        assert_eq!(types, vec![TokenType::Label, TokenType::Number, TokenType::Number, TokenType::Number, TokenType::Newline,
+            TokenType::Number, TokenType::Color, TokenType::Newline,
+            TokenType::Ident, TokenType::Color, TokenType::Newline, TokenType::EOF]);
+        assert_eq!(super::parse_color("#ff0000"), Some(0xFF0000FF));
+    }
+
+    #[test]
+    fn reports_invalid_colors_and_numbers() {
+        let mut t = Tokenizer::new("render 8 20 20\n0 dd00f\n".to_string());
+        assert!(t.start().unwrap_err().to_string().contains("Invalid color 'dd00f' on line 2"));
+        assert_eq!(t.position(), (2, 3));
+
+        let mut t = Tokenizer::new("states 2\n1 *.0 _ r 1a\n".to_string());
+        assert!(t.start().unwrap_err().to_string().contains("Invalid number '1a'"));
+    }
+}
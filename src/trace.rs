@@ -0,0 +1,231 @@
+use std::{fmt, io::{self, Write}};
+
+use crate::{bio::BioRule, observer::SimulationObserver, processor::Point, stats::StepStats};
+
+/// Writes a line for every rule a cell applies, see Processor::add_observer.
+/// Lines are comma separated: generation the rule was applied in, world position of the cell,
+/// id of the rule, and the state of the cell before and after it.
+pub struct TraceObserver<W:Write + Send + Sync> {
+    out:W,
+    generation:u64,
+    origin:(i64, i64),
+    error:Option<io::Error>     // First write that failed, later lines are dropped
+}
+
+impl<W:Write + Send + Sync> TraceObserver<W> {
+    pub fn new(out:W) -> io::Result<TraceObserver<W>> {
+        let mut out = out;
+        writeln!(out, "generation,x,y,rule,before,after")?;
+        Ok(TraceObserver { out, generation:0, origin:(0, 0), error:None })
+    }
+
+    /// Flushes the trace and hands back the writer, or the first error met while writing.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl<W:Write + Send + Sync> SimulationObserver for TraceObserver<W> {
+    fn on_step_start(&mut self, generation:u64, origin:(i64, i64)) {
+        self.generation = generation;
+        self.origin = origin;
+    }
+
+    fn on_rule_fired(&mut self, point:Point, rule:&BioRule) {
+        if self.error.is_some() {
+            return;
+        }
+        let (x, y) = (self.origin.0 + point.x as i64, self.origin.1 + point.y as i64);
+        if let Err(e) = writeln!(self.out, "{},{},{},{:016x},{},{}", self.generation, x, y, rule.id, rule.owner_state, rule.next_state) {
+            self.error = Some(e);
+        }
+    }
+
+    fn on_step_end(&mut self, _stats:&StepStats) {
+        if let (None, Err(e)) = (&self.error, self.out.flush()) {
+            self.error = Some(e);
+        }
+    }
+}
+
+/// A condition that pauses a simulation when it holds.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Breakpoint {
+    RuleFires { rule:u64, at:Option<(i64, i64)> },  // The rule with this id is applied, anywhere or by the cell at a world position.
+    Population { state:usize, count:usize }         // The number of cells in a state reaches a count, from either side.
+}
+
+impl Breakpoint {
+    /// Reads a breakpoint written as 'rule <id> [at <x> <y>]' or 'count <state> <n>'.
+    /// Rule ids are in hex, as written in the stats and trace files.
+    pub fn parse(text:&str) -> Option<Breakpoint> {
+        let words:Vec<&str> = text.split_whitespace().collect();
+        match words[..] {
+            ["rule", id] => Some(Breakpoint::RuleFires { rule:u64::from_str_radix(id, 16).ok()?, at:None }),
+            ["rule", id, "at", x, y] => Some(Breakpoint::RuleFires { rule:u64::from_str_radix(id, 16).ok()?, at:Some((x.parse().ok()?, y.parse().ok()?)) }),
+            ["count", state, count] => Some(Breakpoint::Population { state:state.parse().ok()?, count:count.parse().ok()? }),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Breakpoint::RuleFires { rule, at:None } => write!(f, "rule {:016x} fired", rule),
+            Breakpoint::RuleFires { rule, at:Some((x, y)) } => write!(f, "rule {:016x} fired at ({}, {})", rule, x, y),
+            Breakpoint::Population { state, count } => write!(f, "population of state {} reached {}", state, count)
+        }
+    }
+}
+
+/// Watches for breakpoints during each step, see Processor::break_reason.
+pub struct BreakpointObserver {
+    pub breakpoints:Vec<Breakpoint>,
+    hit:Option<usize>,              // First breakpoint that held during the last step
+    origin:(i64, i64),
+    last_counts:Option<Vec<usize>>  // Cells in each state after the step before, to see counts pass by
+}
+
+impl BreakpointObserver {
+    pub fn new(breakpoints:Vec<Breakpoint>) -> BreakpointObserver {
+        BreakpointObserver { breakpoints, hit:None, origin:(0, 0), last_counts:None }
+    }
+
+    fn mark(&mut self, index:usize) {
+        if self.hit.is_none_or(|h| index < h) {
+            self.hit = Some(index);
+        }
+    }
+}
+
+impl SimulationObserver for BreakpointObserver {
+    fn on_step_start(&mut self, _generation:u64, origin:(i64, i64)) {
+        self.hit = None;
+        self.origin = origin;
+    }
+
+    fn on_rule_fired(&mut self, point:Point, rule:&BioRule) {
+        let pos = (self.origin.0 + point.x as i64, self.origin.1 + point.y as i64);
+        for i in 0..self.breakpoints.len() {
+            if let Breakpoint::RuleFires { rule:wanted, at } = self.breakpoints[i] {
+                if at.is_none_or(|at| at == pos) && rule.id == wanted {
+                    self.mark(i);
+                }
+            }
+        }
+    }
+
+    fn on_step_end(&mut self, stats:&StepStats) {
+        for i in 0..self.breakpoints.len() {
+            if let Breakpoint::Population { state, count } = self.breakpoints[i] {
+                let now = stats.counts.get(state).copied().unwrap_or(0);
+                let before = self.last_counts.as_ref().map(|c| c.get(state).copied().unwrap_or(0));
+                let passed = before.is_some_and(|b| (b < count && now > count) || (b > count && now < count));
+                if now == count || passed {
+                    self.mark(i);
+                }
+            }
+        }
+        self.last_counts = Some(stats.counts.clone());
+    }
+
+    fn break_reason(&self) -> Option<String> {
+        self.hit.map(|i| self.breakpoints[i].to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tokenizer::Tokenizer, parser::Parser, lower::lower, processor::Processor};
+    use std::{io::{self, Write}, sync::{Arc, Mutex}};
+    use super::{Breakpoint, BreakpointObserver, TraceObserver};
+
+    fn mover() -> Processor {
+        let mut t = Tokenizer::new("states 2\n1 ^0.0 _ r 1\nrender 1 10 5\nseed\n0 0 1\n".to_string());
+        t.start().unwrap();
+        let program = lower(&Parser::new(t.tokens).start().unwrap()).ok().unwrap();
+        Processor::new_with_world(program.rule_set, program.render_rules, program.world)
+    }
+
+    #[test]
+    fn parses_breakpoints() {
+        assert_eq!(Breakpoint::parse("rule ab"), Some(Breakpoint::RuleFires { rule:0xab, at:None }));
+        assert_eq!(Breakpoint::parse("rule 00ab at 10 -4"), Some(Breakpoint::RuleFires { rule:0xab, at:Some((10, -4)) }));
+        assert_eq!(Breakpoint::parse(" count 3  0 "), Some(Breakpoint::Population { state:3, count:0 }));
+        assert_eq!(Breakpoint::parse("rule xyz"), None);
+        assert_eq!(Breakpoint::parse("count 3"), None);
+        assert_eq!(Breakpoint::parse("count 3 0").unwrap().to_string(), "population of state 3 reached 0");
+    }
+
+    /// Lets a test read what a trace wrote while the processor still owns it.
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf:&[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn traces_every_rule_applied() {
+        let mut p = mover();
+        let id = p.rule_set.state_rules(1).unwrap()[0].id;
+        let out = Shared(Arc::new(Mutex::new(vec![])));
+        p.add_observer(Box::new(TraceObserver::new(out.clone()).unwrap()));
+        p.step();
+        p.step();
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(text, format!("generation,x,y,rule,before,after\n0,0,0,{:016x},1,1\n1,1,0,{:016x},1,1\n", id, id));
+    }
+
+    #[test]
+    fn breaks_when_a_rule_fires_at_a_position() {
+        let mut p = mover();
+        let id = p.rule_set.state_rules(1).unwrap()[0].id;
+        p.add_observer(Box::new(BreakpointObserver::new(vec![
+            Breakpoint::RuleFires { rule:id, at:Some((3, 0)) },
+            Breakpoint::Population { state:1, count:0 }
+        ])));
+        for _ in 0..3 {
+            p.step();
+            assert_eq!(p.break_reason(), None);
+        }
+        p.step();
+        assert_eq!(p.break_reason(), Some(format!("rule {:016x} fired at (3, 0)", id)));
+        p.step();
+        assert_eq!(p.break_reason(), None);
+    }
+
+    #[test]
+    fn tells_apart_rules_that_only_differ_in_their_move() {
+        // The last matching rule wins, so only the one moving left fires.
+        let mut t = Tokenizer::new("states 2\n1 ^0.0 _ r 1\n1 ^0.0 _ l 1\nrender 1 10 1\nseed\n5 0 1\n".to_string());
+        t.start().unwrap();
+        let program = lower(&Parser::new(t.tokens).start().unwrap()).ok().unwrap();
+        let mut p:Processor = Processor::new_with_world(program.rule_set, program.render_rules, program.world);
+        let (right, left) = (p.rule_set.state_rules(1).unwrap()[0].id, p.rule_set.state_rules(1).unwrap()[1].id);
+        assert_ne!(right, left);
+
+        let out = Shared(Arc::new(Mutex::new(vec![])));
+        p.add_observer(Box::new(TraceObserver::new(out.clone()).unwrap()));
+        p.add_observer(Box::new(BreakpointObserver::new(vec![Breakpoint::RuleFires { rule:right, at:None }])));
+        p.step();
+        assert_eq!(p.break_reason(), None);
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(text, format!("generation,x,y,rule,before,after\n0,5,0,{:016x},1,1\n", left));
+
+        p.add_observer(Box::new(BreakpointObserver::new(vec![Breakpoint::RuleFires { rule:left, at:None }])));
+        p.step();
+        assert_eq!(p.break_reason(), Some(format!("rule {:016x} fired", left)));
+    }
+
+}